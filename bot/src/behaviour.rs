@@ -0,0 +1,101 @@
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use shared::*;
+
+/// How far off the tube's centre line a steering bot drifts before correcting,
+/// sideways or vertically.
+const CENTRE_TOLERANCE: f32 = PLAYER_RADIUS;
+/// Stamina at which a steering bot starts boosting, and where it stops.
+const BOOST_START: f32 = 60.0;
+const BOOST_STOP: f32 = 10.0;
+/// Ticks a random bot holds the same keys.
+const RANDOM_HOLD_TICKS: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Behaviour {
+    /// Swim for the ampulla, keeping centred and boosting when rested
+    Steer,
+    /// Hold random keys, mostly forward, changing twice a second
+    Random,
+    /// Send neutral input only
+    Idle,
+}
+
+/// Produces one bot's inputs.
+pub struct Driver {
+    behaviour: Behaviour,
+    rng: StdRng,
+    boosting: bool,
+    held: InputFrame,
+    held_ticks: u32,
+}
+
+impl Driver {
+    pub fn new(behaviour: Behaviour, seed: u64) -> Self {
+        Self {
+            behaviour,
+            rng: StdRng::seed_from_u64(seed),
+            boosting: false,
+            held: InputFrame::default(),
+            held_ticks: 0,
+        }
+    }
+
+    /// Input for `tick`, given the bot's last known state.
+    pub fn input(&mut self, tick: u32, kin: Option<&PlayerKinematics>) -> InputFrame {
+        match self.behaviour {
+            Behaviour::Steer => self.steer(tick, kin),
+            Behaviour::Random => self.random(tick),
+            Behaviour::Idle => InputFrame {
+                tick,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn steer(&mut self, tick: u32, kin: Option<&PlayerKinematics>) -> InputFrame {
+        let Some(kin) = kin else {
+            return InputFrame {
+                tick,
+                up: true,
+                ..Default::default()
+            };
+        };
+        if kin.stamina >= BOOST_START {
+            self.boosting = true;
+        } else if kin.stamina <= BOOST_STOP {
+            self.boosting = false;
+        }
+        InputFrame {
+            tick,
+            up: true,
+            left: kin.position.z > CENTRE_TOLERANCE,
+            right: kin.position.z < -CENTRE_TOLERANCE,
+            ascend: kin.position.y < -CENTRE_TOLERANCE,
+            descend: kin.position.y > CENTRE_TOLERANCE,
+            boost: self.boosting,
+            ..Default::default()
+        }
+    }
+
+    fn random(&mut self, tick: u32) -> InputFrame {
+        if self.held_ticks == 0 {
+            self.held = InputFrame {
+                up: self.rng.gen_bool(0.75),
+                down: self.rng.gen_bool(0.1),
+                left: self.rng.gen_bool(0.3),
+                right: self.rng.gen_bool(0.3),
+                ascend: self.rng.gen_bool(0.3),
+                descend: self.rng.gen_bool(0.3),
+                boost: self.rng.gen_bool(0.3),
+                ..Default::default()
+            };
+            self.held_ticks = RANDOM_HOLD_TICKS;
+        }
+        self.held_ticks -= 1;
+        InputFrame {
+            tick,
+            ..self.held.clone()
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, SystemTime},
+};
+
+use bevy_renet::netcode::{ClientAuthentication, ConnectToken, NetcodeClientTransport};
+use bevy_renet::renet::RenetClient;
+use rand::Rng;
+use shared::*;
+
+use crate::behaviour::{Behaviour, Driver};
+
+/// What a bot saw over its lifetime, for the final report.
+#[derive(Debug, Default)]
+pub struct BotStats {
+    /// Why the bot gave up, if it did.
+    pub failure: Option<String>,
+    pub room_code: Option<String>,
+    /// Joined after the race started and could only watch.
+    pub spectating: bool,
+    pub snapshots: u32,
+    first_snapshot: Option<f64>,
+    last_snapshot: Option<f64>,
+    pub rtt: Option<f64>,
+    pub leaderboard: Option<Vec<LeaderboardEntry>>,
+}
+
+impl BotStats {
+    /// Snapshots received per second between the first and the last.
+    pub fn snapshot_rate(&self) -> Option<f64> {
+        let span = self.last_snapshot? - self.first_snapshot?;
+        (span > 0.0).then(|| f64::from(self.snapshots - 1) / span)
+    }
+}
+
+/// One headless connection, driven like a player would drive the client.
+pub struct Bot {
+    pub index: usize,
+    name: String,
+    client: RenetClient,
+    transport: NetcodeClientTransport,
+    driver: Driver,
+    /// Room to join once known; `None` with `creates_room` makes a new one.
+    pub target_room: Option<String>,
+    pub creates_room: bool,
+    /// Racers the bot waits for before readying up.
+    group_size: usize,
+    fill_timeout: f64,
+    greeted: bool,
+    join_sent: bool,
+    joined_at: Option<f64>,
+    ready: bool,
+    phase: Option<RoomPhase>,
+    /// Racers in the room, from the last `RoomState`.
+    players: usize,
+    tick_rate: u32,
+    clock: ClockSync,
+    last_ping: Option<f64>,
+    input_tick: u32,
+    pending: InputHistory,
+    /// Time not yet turned into input ticks.
+    accumulator: f64,
+    baselines: VecDeque<(u32, Vec<EntitySnapshot>)>,
+    kin: Option<PlayerKinematics>,
+    pub stats: BotStats,
+}
+
+impl Bot {
+    pub fn connect(
+        index: usize,
+        server_addr: SocketAddr,
+        private_key: Option<&[u8; PRIVATE_KEY_BYTES]>,
+        behaviour: Behaviour,
+        group_size: usize,
+        fill_timeout: f64,
+    ) -> Result<Self, String> {
+        let name = format!("Bot-{index}");
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let socket =
+            UdpSocket::bind("0.0.0.0:0").map_err(|err| format!("could not open socket: {err}"))?;
+
+        let client_id: u64 = rand::thread_rng().gen();
+        let authentication = match private_key {
+            // Sign our own tokens, as issue_token would
+            Some(private_key) => {
+                let user_data = encode_user_data(&name).map_err(|err| err.to_string())?;
+                let connect_token = ConnectToken::generate(
+                    current_time,
+                    PROTOCOL_ID,
+                    TOKEN_EXPIRE_SECONDS,
+                    client_id,
+                    TOKEN_TIMEOUT_SECONDS,
+                    vec![server_addr],
+                    Some(&user_data),
+                    private_key,
+                )
+                .map_err(|err| format!("could not generate token: {err}"))?;
+                ClientAuthentication::Secure { connect_token }
+            }
+            None => ClientAuthentication::Unsecure {
+                protocol_id: PROTOCOL_ID,
+                client_id,
+                server_addr,
+                user_data: None,
+            },
+        };
+        let transport = NetcodeClientTransport::new(current_time, authentication, socket)
+            .map_err(|err| format!("could not start transport: {err}"))?;
+
+        Ok(Self {
+            index,
+            name,
+            client: RenetClient::new(connection_config()),
+            transport,
+            driver: Driver::new(behaviour, index as u64),
+            target_room: None,
+            creates_room: false,
+            group_size,
+            fill_timeout,
+            greeted: false,
+            join_sent: false,
+            joined_at: None,
+            ready: false,
+            phase: None,
+            players: 0,
+            tick_rate: TICK_RATE,
+            clock: ClockSync::default(),
+            last_ping: None,
+            input_tick: 0,
+            pending: InputHistory::default(),
+            accumulator: 0.0,
+            baselines: VecDeque::new(),
+            kin: None,
+            stats: BotStats::default(),
+        })
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// Still connected and waiting for, or taking part in, a race.
+    pub fn active(&self) -> bool {
+        self.stats.failure.is_none() && self.stats.leaderboard.is_none()
+    }
+
+    /// Advances the connection by `dt`; `now` is seconds since the run began.
+    pub fn update(&mut self, dt: Duration, now: f64) {
+        if !self.active() {
+            return;
+        }
+        self.client.update(dt);
+        if let Err(err) = self.transport.update(dt, &mut self.client) {
+            self.stats.failure = Some(format!("transport error: {err}"));
+            return;
+        }
+
+        if self.client.is_connected() {
+            self.handshake(now);
+            self.receive(now);
+            self.ready_up(now);
+            self.ping(now);
+            self.drive(dt.as_secs_f64(), now);
+        } else if self.client.is_disconnected() {
+            let reason = self
+                .client
+                .disconnect_reason()
+                .map(|reason| format!("disconnected: {reason:?}"))
+                .unwrap_or_else(|| "disconnected".into());
+            self.stats.failure = Some(reason);
+            return;
+        }
+
+        if let Err(err) = self.transport.send_packets(&mut self.client) {
+            self.stats.failure = Some(format!("transport error: {err}"));
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        self.transport.disconnect();
+    }
+
+    fn send(&mut self, msg: &ClientMessage) {
+        if let Ok(bytes) = bincode::serialize(msg) {
+            self.client.send_message(msg.channel(), bytes);
+        }
+    }
+
+    fn handshake(&mut self, now: f64) {
+        if !self.greeted {
+            self.send(&ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                build: BUILD.into(),
+            });
+            self.greeted = true;
+        }
+        if self.join_sent || (self.target_room.is_none() && !self.creates_room) {
+            return;
+        }
+        self.send(&ClientMessage::JoinRoom {
+            name: self.name.clone(),
+            room_code: self.target_room.clone(),
+            spectate: false,
+        });
+        self.join_sent = true;
+        self.joined_at = Some(now);
+    }
+
+    fn receive(&mut self, now: f64) {
+        let mut messages = Vec::new();
+        for channel in Channel::ALL {
+            while let Some(message) = self.client.receive_message(channel) {
+                messages.push(message);
+            }
+        }
+        for message in messages {
+            let Ok(msg) = bincode::deserialize::<ServerMessage>(&message) else {
+                continue;
+            };
+            match msg {
+                ServerMessage::JoinRejected { reason } => {
+                    self.stats.failure = Some(format!("rejected: {reason}"));
+                    return;
+                }
+                ServerMessage::Kicked { reason } => {
+                    self.stats.failure = Some(reason.to_string());
+                    return;
+                }
+                ServerMessage::Joined {
+                    room_code,
+                    session_token,
+                    tick_rate,
+                } => {
+                    self.stats.room_code = Some(room_code);
+                    self.stats.spectating = session_token.is_none();
+                    if tick_rate != self.tick_rate {
+                        self.tick_rate = tick_rate;
+                        self.clock = ClockSync::new(tick_rate);
+                        self.pending = InputHistory::new(tick_rate);
+                    }
+                }
+                ServerMessage::RoomState { players, state, .. } => {
+                    self.players = players.len();
+                    self.phase = Some(state);
+                }
+                ServerMessage::Pong {
+                    client_time,
+                    server_tick,
+                } => {
+                    self.clock.observe(client_time, now, server_tick);
+                    self.stats.rtt = self.clock.rtt();
+                }
+                ServerMessage::Snapshot {
+                    tick,
+                    last_input,
+                    entities,
+                    ..
+                } => self.apply_snapshot(tick, last_input, entities, now),
+                ServerMessage::SnapshotDelta {
+                    tick,
+                    last_input,
+                    baseline,
+                    changed,
+                    removed,
+                    ..
+                } => {
+                    let entities = self
+                        .baselines
+                        .iter()
+                        .find(|(t, _)| *t == baseline)
+                        .and_then(|(_, base)| apply_delta(base, &changed, &removed));
+                    if let Some(entities) = entities {
+                        self.apply_snapshot(tick, last_input, entities, now);
+                    }
+                }
+                ServerMessage::RaceFinished { leaderboard } => {
+                    self.stats.leaderboard = Some(leaderboard);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Readies up once the room holds the whole group, or the wait is over.
+    fn ready_up(&mut self, now: f64) {
+        let waited = self
+            .joined_at
+            .is_some_and(|joined| now - joined >= self.fill_timeout);
+        if self.ready
+            || self.stats.spectating
+            || self.phase != Some(RoomPhase::Lobby)
+            || (self.players < self.group_size && !waited)
+        {
+            return;
+        }
+        self.send(&ClientMessage::SetReady { ready: true });
+        if self.creates_room {
+            // Covers humans in the room that never ready up
+            self.send(&ClientMessage::StartRace);
+        }
+        self.ready = true;
+    }
+
+    fn apply_snapshot(
+        &mut self,
+        tick: u32,
+        last_input: Option<u32>,
+        entities: Vec<EntitySnapshot>,
+        now: f64,
+    ) {
+        if self
+            .baselines
+            .back()
+            .is_some_and(|(newest, _)| tick <= *newest)
+        {
+            return;
+        }
+        if let Some(last_input) = last_input {
+            self.pending.ack(last_input);
+        }
+        let client_id = self.transport.client_id();
+        if let Some(own) = entities.iter().find(|e| e.id == client_id) {
+            self.kin = Some(own.kinematics());
+        }
+        if self.baselines.len() == SNAPSHOT_HISTORY {
+            self.baselines.pop_front();
+        }
+        self.baselines.push_back((tick, entities));
+        self.send(&ClientMessage::SnapshotAck { tick });
+
+        self.stats.snapshots += 1;
+        self.stats.first_snapshot.get_or_insert(now);
+        self.stats.last_snapshot = Some(now);
+    }
+
+    fn ping(&mut self, now: f64) {
+        if self
+            .last_ping
+            .is_some_and(|last| now - last < PING_INTERVAL_SECONDS)
+        {
+            return;
+        }
+        self.last_ping = Some(now);
+        self.send(&ClientMessage::Ping { client_time: now });
+    }
+
+    /// Sends one input per elapsed tick, like the client's fixed update.
+    fn drive(&mut self, dt: f64, now: f64) {
+        if self.stats.spectating || self.stats.room_code.is_none() {
+            return;
+        }
+        let tick_seconds = 1.0 / f64::from(self.tick_rate);
+        // Don't try to catch up on a long stall
+        self.accumulator = (self.accumulator + dt).min(tick_seconds * 4.0);
+        while self.accumulator >= tick_seconds {
+            self.accumulator -= tick_seconds;
+            let Some(next) = self.clock.next_input_tick(now, self.input_tick) else {
+                continue;
+            };
+            self.input_tick = next;
+            let input = self.driver.input(next, self.kin.as_ref());
+            self.pending.record(input);
+            let frames = self.pending.latest(INPUT_REDUNDANCY);
+            self.send(&ClientMessage::Inputs(frames));
+        }
+    }
+}
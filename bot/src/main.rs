@@ -0,0 +1,195 @@
+//! Headless bots for load and soak testing a server.
+//!
+//! Usage: `bot [server-addr] --bots 32 --per-room 8 --duration 300`
+//!
+//! Bots sign their own connect tokens when `ODYSSEY_PRIVATE_KEY` is set.
+
+mod behaviour;
+mod bot;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use shared::*;
+
+use crate::{behaviour::Behaviour, bot::Bot};
+
+#[derive(Debug, Parser)]
+#[command(about = "Headless bots for load and soak testing")]
+struct Args {
+    #[arg(default_value_t = ClientSettings::default().server_addr)]
+    server_addr: SocketAddr,
+    /// Bots to connect
+    #[arg(short = 'n', long, default_value_t = MAX_PLAYERS)]
+    bots: usize,
+    /// Bots sharing each room
+    #[arg(long, default_value_t = MAX_PLAYERS)]
+    per_room: usize,
+    /// Put every bot in this existing room instead of creating rooms
+    #[arg(long)]
+    room_code: Option<String>,
+    #[arg(long, value_enum, default_value_t = Behaviour::Steer)]
+    behaviour: Behaviour,
+    /// Give up after this many seconds
+    #[arg(long, default_value_t = 300)]
+    duration: u64,
+    /// Seconds to wait for a room to fill before readying up anyway
+    #[arg(long, default_value_t = 10)]
+    fill_timeout: u64,
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.bots == 0 || args.per_room == 0 {
+        fail("--bots and --per-room must be at least 1");
+    }
+    let private_key = std::env::var(PRIVATE_KEY_ENV)
+        .ok()
+        .map(|hex| parse_private_key(&hex).unwrap_or_else(|err| fail(&err.to_string())));
+
+    let group_size = match &args.room_code {
+        Some(_) => args.bots,
+        None => args.per_room,
+    };
+    let mut bots: Vec<Bot> = Vec::new();
+    let mut failed = Vec::new();
+    for index in 0..args.bots {
+        match Bot::connect(
+            index,
+            args.server_addr,
+            private_key.as_ref(),
+            args.behaviour,
+            group_size,
+            args.fill_timeout as f64,
+        ) {
+            Ok(mut bot) => {
+                match &args.room_code {
+                    Some(code) => bot.target_room = Some(code.clone()),
+                    // The first bot of each group that manages to connect
+                    None => {
+                        bot.creates_room = !bots
+                            .iter()
+                            .any(|other| other.index / args.per_room == index / args.per_room)
+                    }
+                }
+                bots.push(bot);
+            }
+            Err(err) => failed.push((index, err)),
+        }
+    }
+
+    let tick = Duration::from_secs_f64(1.0 / f64::from(TICK_RATE));
+    let start = Instant::now();
+    let mut last = start;
+    while start.elapsed() < Duration::from_secs(args.duration)
+        && bots.iter().any(|bot| bot.active())
+    {
+        let now = Instant::now();
+        let dt = now - last;
+        last = now;
+        let seconds = (now - start).as_secs_f64();
+        for bot in &mut bots {
+            bot.update(dt, seconds);
+        }
+        assign_rooms(&mut bots, args.per_room);
+        thread::sleep(tick.saturating_sub(now.elapsed()));
+    }
+    for bot in &mut bots {
+        bot.disconnect();
+    }
+
+    report(&bots, &failed);
+    if !failed.is_empty() || bots.iter().any(|bot| bot.stats.failure.is_some()) {
+        std::process::exit(1);
+    }
+}
+
+/// Sends each group's followers into the room its leader created, or fails
+/// them if the leader never got one.
+fn assign_rooms(bots: &mut [Bot], per_room: usize) {
+    let mut leaders = HashMap::new();
+    for bot in bots.iter().filter(|bot| bot.creates_room) {
+        let room = match (&bot.stats.room_code, &bot.stats.failure) {
+            (Some(code), _) => Ok(code.clone()),
+            (None, Some(_)) => Err(()),
+            (None, None) => continue,
+        };
+        leaders.insert(bot.index / per_room, room);
+    }
+    for bot in bots {
+        if bot.creates_room || bot.target_room.is_some() || !bot.active() {
+            continue;
+        }
+        match leaders.get(&(bot.index / per_room)) {
+            Some(Ok(code)) => bot.target_room = Some(code.clone()),
+            Some(Err(())) => bot.stats.failure = Some("room creator failed".into()),
+            None => {}
+        }
+    }
+}
+
+fn report(bots: &[Bot], failed: &[(usize, String)]) {
+    let connected = bots.iter().filter(|bot| bot.stats.room_code.is_some());
+    let spectators = connected.clone().filter(|bot| bot.stats.spectating).count();
+    println!(
+        "Bots: {} joined ({spectators} as spectators), {} failed",
+        connected.count(),
+        failed.len() + bots.iter().filter(|b| b.stats.failure.is_some()).count()
+    );
+    for (index, err) in failed {
+        println!("  Bot-{index}: {err}");
+    }
+    for bot in bots {
+        if let Some(err) = &bot.stats.failure {
+            println!("  Bot-{}: {err}", bot.index);
+        }
+    }
+
+    let rates: Vec<f64> = bots
+        .iter()
+        .filter_map(|b| b.stats.snapshot_rate())
+        .collect();
+    if !rates.is_empty() {
+        let avg = rates.iter().sum::<f64>() / rates.len() as f64;
+        let min = rates.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = rates.iter().cloned().fold(0.0, f64::max);
+        println!("Snapshots/s: avg {avg:.1}, min {min:.1}, max {max:.1}");
+    }
+    let rtts: Vec<f64> = bots.iter().filter_map(|b| b.stats.rtt).collect();
+    if !rtts.is_empty() {
+        let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        println!("RTT: avg {:.0} ms", avg * 1000.0);
+    }
+
+    // Every bot in a room gets the same leaderboard; print it once
+    let mut leaderboards = BTreeMap::new();
+    for bot in bots {
+        if let (Some(code), Some(board)) = (&bot.stats.room_code, &bot.stats.leaderboard) {
+            leaderboards
+                .entry(code.clone())
+                .or_insert((board, bot.tick_rate()));
+        }
+    }
+    for (code, (board, tick_rate)) in leaderboards {
+        println!("Room {code}:");
+        for (rank, entry) in board.iter().enumerate() {
+            println!(
+                "  {}. {} {} ticks ({:.2}s)",
+                rank + 1,
+                entry.name,
+                entry.ticks,
+                f64::from(entry.ticks) / f64::from(tick_rate)
+            );
+        }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
@@ -0,0 +1,38 @@
+use bevy::prelude::*;
+use shared::*;
+
+use crate::{LocalPlayer, PlayerAvatar, SnapshotBaselines, Velocity};
+
+/// Recent server states of an avatar, replayed slightly in the past
+#[derive(Component, Default, Deref, DerefMut)]
+pub struct RemoteMotion(pub InterpolationBuffer);
+
+/// Server tick remote avatars are currently drawn at
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct RenderClock(InterpolationClock);
+
+/// Move every avatar except our own along its buffered snapshots
+pub fn interpolate_remote_avatars(
+    time: Res<Time>,
+    player: Option<Res<LocalPlayer>>,
+    baselines: Res<SnapshotBaselines>,
+    mut clock: ResMut<RenderClock>,
+    mut avatars: Query<(&PlayerAvatar, &RemoteMotion, &mut Transform, &mut Velocity)>,
+) {
+    let Some(player) = player else { return };
+    let Some(newest) = baselines.newest() else {
+        return;
+    };
+    let tick = clock.advance(time.delta_secs_f64(), newest);
+
+    for (avatar, motion, mut transform, mut velocity) in avatars.iter_mut() {
+        // Our own avatar is placed by prediction
+        if avatar.id == player.client_id {
+            continue;
+        }
+        if let Some((position, vel)) = motion.sample(tick, player.tick_rate) {
+            transform.translation = position;
+            **velocity = vel;
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Bevy glue for the client's end of a `shared::LoopbackHub`.
+
+use bevy::prelude::*;
+use bevy_renet::renet::RenetClient;
+use bevy_renet::{RenetClientPlugin, RenetReceive, RenetSend};
+use shared::*;
+
+/// Lets a client connect through `hub`: insert a `LoopbackClient` from
+/// `Loopback::connect` where a `NetcodeClientTransport` would go.
+pub struct LoopbackClientPlugin {
+    pub hub: LoopbackHub,
+}
+
+/// Hub to open loopback connections on.
+#[derive(Resource, Clone, Deref)]
+pub struct Loopback(pub LoopbackHub);
+
+/// The client's end of its loopback connection.
+#[derive(Resource, Deref, DerefMut)]
+pub struct LoopbackClient(pub LoopbackClientTransport);
+
+impl Plugin for LoopbackClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Loopback(self.hub.clone()))
+            .add_systems(
+                PreUpdate,
+                receive_client_packets
+                    .in_set(RenetReceive)
+                    .run_if(resource_exists::<LoopbackClient>)
+                    .run_if(resource_exists::<RenetClient>)
+                    .after(RenetClientPlugin::update_system),
+            )
+            .add_systems(
+                PostUpdate,
+                send_client_packets
+                    .in_set(RenetSend)
+                    .run_if(resource_exists::<LoopbackClient>)
+                    .run_if(resource_exists::<RenetClient>),
+            );
+    }
+}
+
+fn receive_client_packets(
+    time: Res<Time>,
+    mut transport: ResMut<LoopbackClient>,
+    mut client: ResMut<RenetClient>,
+) {
+    transport.update(time.delta(), &mut client);
+}
+
+fn send_client_packets(mut transport: ResMut<LoopbackClient>, mut client: ResMut<RenetClient>) {
+    transport.send_packets(&mut client);
+}
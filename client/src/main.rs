@@ -1,16 +1,84 @@
-use std::{net::UdpSocket, time::SystemTime};
+mod interpolation;
+mod loopback;
+mod prediction;
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::SystemTime,
+};
 
 use bevy::math::primitives::{Capsule3d, Cylinder, Sphere};
 use bevy::prelude::*;
 use bevy::time::Fixed;
 use bevy::ui::{Node, PositionType, Val};
 use bevy::window::WindowResolution;
-use bevy_renet::netcode::{ClientAuthentication, NetcodeClientPlugin, NetcodeClientTransport};
-use bevy_renet::renet::{ConnectionConfig, RenetClient};
+use bevy_renet::netcode::{
+    ClientAuthentication, ConnectToken, NetcodeClientPlugin, NetcodeClientTransport,
+};
+use bevy_renet::renet::RenetClient;
 use bevy_renet::RenetClientPlugin;
+use clap::Parser;
+use interpolation::{interpolate_remote_avatars, RemoteMotion, RenderClock};
+use loopback::{Loopback, LoopbackClient, LoopbackClientPlugin};
+use prediction::{render_prediction, Prediction};
 use rand::Rng;
+use server::loopback::spawn_server;
 use shared::*; // PROTOCOL_ID, TICK_RATE, TRACK_LENGTH, REGION_MARKERS, RegionId, InputFrame, ClientMessage, etc.
 
+/// Wait before the first reconnection attempt; doubles up to 8x after that
+const RECONNECT_DELAY_SECONDS: f64 = 1.0;
+/// The host's [ and ] scale both swim speeds by this
+const SPEED_STEP: f32 = 1.1;
+/// How far inside the tube wall the follow camera stays
+const CAMERA_WALL_MARGIN: f32 = 20.0;
+
+/// Odyssey client. Flags take precedence over the config file.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Code of a friend's room to join
+    room_code: Option<String>,
+    /// TOML file with any of the settings below, in snake_case
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Server to connect to [default: 127.0.0.1:5000]
+    #[arg(long)]
+    server_addr: Option<SocketAddr>,
+    #[arg(long)]
+    name: Option<String>,
+    /// Watch the race instead of taking part
+    #[arg(long)]
+    spectate: bool,
+    /// Connect token file, for servers that only accept tokens
+    #[arg(long, env = CONNECT_TOKEN_ENV)]
+    connect_token: Option<PathBuf>,
+    /// Race alone on a server run inside this process
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Args {
+    /// The config file's settings with flags applied on top, validated.
+    fn settings(self) -> Result<ClientSettings, ConfigError> {
+        let mut settings: ClientSettings = load_config(self.config.as_deref())?;
+        settings.server_addr = self.server_addr.unwrap_or(settings.server_addr);
+        settings.name = self.name.or(settings.name);
+        settings.room_code = self.room_code.or(settings.room_code);
+        settings.spectate |= self.spectate;
+        settings.connect_token = self.connect_token.or(settings.connect_token);
+        settings.offline |= self.offline;
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// Validated settings from the command line and config file
+#[derive(Resource, Deref)]
+struct Settings(ClientSettings);
+
 /// Local client info
 #[derive(Resource)]
 struct LocalPlayer {
@@ -18,11 +86,109 @@ struct LocalPlayer {
     room_code: Option<String>,
     joined: bool,
     client_id: u64,
+    /// Why the server refused us, shown instead of the connection status
+    rejected: Option<JoinRejectReason>,
+    /// Set once the host removes us; we stay disconnected after that
+    kicked: Option<KickReason>,
+    phase: Option<RoomPhase>,
+    /// Lets us reclaim our slot after a dropped connection
+    session_token: Option<u64>,
+    /// Watching rather than racing, by choice or because we joined late
+    spectating: bool,
+    /// Racers in the room, as of the last RoomState
+    racers: Vec<PlayerSummary>,
+    spectator_count: usize,
+    locked: bool,
+    banned: Vec<String>,
+    /// Race settings of our room, which prediction must match
+    room_settings: RoomSettings,
+    /// Racer the host's moderation keys act on
+    selected: Option<u64>,
+    /// Server ticks per second, learned when joining
+    tick_rate: u32,
+}
+
+impl LocalPlayer {
+    /// Whether the server is currently moving players around
+    fn simulating(&self) -> bool {
+        matches!(self.phase, Some(RoomPhase::Countdown | RoomPhase::Racing))
+    }
+
+    /// Seconds simulated per server tick
+    fn tick_dt(&self) -> f32 {
+        1.0 / self.tick_rate as f32
+    }
+
+    fn is_host(&self) -> bool {
+        self.racers
+            .iter()
+            .any(|p| p.id == self.client_id && p.is_host)
+    }
+
+    /// Another racer after `after` by id, wrapping around
+    fn next_racer(&self, after: u64) -> Option<u64> {
+        let mut ids: Vec<u64> = self
+            .racers
+            .iter()
+            .map(|p| p.id)
+            .filter(|id| *id != self.client_id)
+            .collect();
+        ids.sort_unstable();
+        ids.iter().find(|id| **id > after).or(ids.first()).copied()
+    }
 }
 
-/// Monotonic tick for InputFrame
+/// Server tick of the last InputFrame sent
+#[derive(Resource, Default)]
+struct InputTick(u32);
+
+/// Automatic reconnection after the connection drops
+#[derive(Resource, Default)]
+struct Reconnect {
+    attempts: u32,
+    /// Real time the next attempt is due at
+    next_attempt: Option<f64>,
+}
+
+/// Local address of our UDP socket. Reconnects bind it again, since
+/// netcode turns down a connect token it has seen from another address.
+#[derive(Resource, Clone, Copy)]
+struct LocalAddr(SocketAddr);
+
+/// Round-trip time and server tick estimate, kept up to date by pings
+#[derive(Resource, Default, Deref, DerefMut)]
+struct ServerClock(ClockSync);
+
+/// Inputs the server hasn't confirmed yet; resent with every new frame
+#[derive(Resource, Default, Deref, DerefMut)]
+struct PendingInputs(InputHistory);
+
+/// Recently reconstructed snapshots, kept as baselines for server deltas
 #[derive(Resource, Default)]
-struct SnapshotTick(u32);
+struct SnapshotBaselines {
+    history: VecDeque<(u32, Vec<EntitySnapshot>)>,
+}
+
+impl SnapshotBaselines {
+    fn get(&self, tick: u32) -> Option<&[EntitySnapshot]> {
+        self.history
+            .iter()
+            .find(|(t, _)| *t == tick)
+            .map(|(_, entities)| entities.as_slice())
+    }
+
+    /// Tick of the most recent snapshot applied
+    fn newest(&self) -> Option<u32> {
+        self.history.back().map(|(tick, _)| *tick)
+    }
+
+    fn record(&mut self, tick: u32, entities: Vec<EntitySnapshot>) {
+        if self.history.len() == SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((tick, entities));
+    }
+}
 
 /// Tag for player's sperm avatar
 #[derive(Component)]
@@ -46,8 +212,19 @@ struct FollowCamera {
 #[derive(Component)]
 struct HudText;
 
+/// Tunnel segment or egg, rebuilt when the room switches track
+#[derive(Component)]
+struct TrackPiece;
+
 fn main() {
-    App::new()
+    let settings = Args::parse().settings().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let offline = settings.offline;
+    let mut app = App::new();
+    app
         // Window + renderer
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -61,7 +238,14 @@ fn main() {
         // Networking
         .add_plugins(RenetClientPlugin)
         .add_plugins(NetcodeClientPlugin)
-        .insert_resource(SnapshotTick::default())
+        .insert_resource(Settings(settings))
+        .insert_resource(InputTick::default())
+        .insert_resource(ServerClock::default())
+        .insert_resource(Reconnect::default())
+        .insert_resource(PendingInputs::default())
+        .insert_resource(SnapshotBaselines::default())
+        .insert_resource(Prediction::default())
+        .insert_resource(RenderClock::default())
         .insert_resource(ClearColor(Color::srgb(0.02, 0.02, 0.08)))
         // Startup: scene + UI + connection
         .add_systems(Startup, (setup_scene, setup_ui, start_connection))
@@ -69,8 +253,16 @@ fn main() {
         .add_systems(
             Update,
             (
+                reconnect,
                 poll_connection_status,
+                sync_tick_rate.after(apply_snapshots),
+                send_pings,
+                toggle_network_conditions,
+                host_controls,
                 apply_snapshots,
+                rebuild_track,
+                render_prediction.after(apply_snapshots),
+                interpolate_remote_avatars.after(apply_snapshots),
                 assign_follow_target,
                 camera_follow_target,
                 update_hud,
@@ -78,8 +270,15 @@ fn main() {
         )
         // Fixed tick for input sending
         .insert_resource(Time::<Fixed>::from_hz(TICK_RATE as f64))
-        .add_systems(FixedUpdate, send_inputs)
-        .run();
+        .add_systems(FixedUpdate, send_inputs);
+
+    // Single player: host the race ourselves, with no sockets involved
+    if offline {
+        let hub = LoopbackHub::new();
+        spawn_server(ServerSettings::default(), hub.clone());
+        app.add_plugins(LoopbackClientPlugin { hub });
+    }
+    app.run();
 }
 
 /// Create camera, lights, tunnel and egg
@@ -130,8 +329,31 @@ fn setup_scene(
         ));
     }
 
-    spawn_tunnel(&mut commands, &mut meshes, &mut materials);
-    spawn_egg(&mut commands, &mut meshes, &mut materials);
+    spawn_tunnel(&mut commands, &mut meshes, &mut materials, Track::default());
+    spawn_egg(&mut commands, &mut meshes, &mut materials, Track::default());
+}
+
+/// Swap the tunnel and egg for those of the room's track when it changes
+fn rebuild_track(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    player: Option<Res<LocalPlayer>>,
+    pieces: Query<Entity, With<TrackPiece>>,
+    mut built: Local<Track>,
+) {
+    let Some(track) = player.map(|p| p.room_settings.track) else {
+        return;
+    };
+    if track == *built {
+        return;
+    }
+    for entity in &pieces {
+        commands.entity(entity).despawn();
+    }
+    spawn_tunnel(&mut commands, &mut meshes, &mut materials, track);
+    spawn_egg(&mut commands, &mut meshes, &mut materials, track);
+    *built = track;
 }
 
 /// Simple HUD in the top-left corner
@@ -157,6 +379,7 @@ fn spawn_tunnel(
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
+    track: Track,
 ) {
     let regions = [
         RegionId::Vagina,
@@ -167,8 +390,8 @@ fn spawn_tunnel(
         RegionId::Ampulla,
     ];
 
-    // Each pair of REGION_MARKERS describes a segment
-    for (idx, segment) in REGION_MARKERS.windows(2).enumerate() {
+    // Each pair of region markers describes a segment
+    for (idx, segment) in track.markers().windows(2).enumerate() {
         let start = segment[0];
         let end = segment[1];
         let length = end - start;
@@ -188,6 +411,7 @@ fn spawn_tunnel(
             MeshMaterial3d(material_handle),
             Transform::from_xyz(center, 0.0, 0.0)
                 .with_rotation(Quat::from_rotation_z(-std::f32::consts::FRAC_PI_2)),
+            TrackPiece,
         ));
     }
 }
@@ -196,8 +420,9 @@ fn spawn_egg(
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
+    track: Track,
 ) {
-    let egg_pos = Vec3::new(TRACK_LENGTH + 120.0, 0.0, 0.0);
+    let egg_pos = Vec3::new(track.length() + 120.0, 0.0, 0.0);
     let egg_mesh = meshes.add(Mesh::from(Sphere::new(48.0)));
     let egg_mat = materials.add(StandardMaterial {
         base_color: Color::srgb(1.0, 0.9, 0.8),
@@ -211,80 +436,360 @@ fn spawn_egg(
         Mesh3d(egg_mesh),
         MeshMaterial3d(egg_mat),
         Transform::from_translation(egg_pos),
+        TrackPiece,
     ));
 }
 
 /// Create Renet client + transport + LocalPlayer
-fn start_connection(mut commands: Commands) {
-    let client_id: u64 = rand::thread_rng().gen();
+fn start_connection(
+    mut commands: Commands,
+    settings: Res<Settings>,
+    loopback: Option<Res<Loopback>>,
+) {
+    let client_id =
+        connect(&mut commands, &settings, loopback.as_deref(), None).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            std::process::exit(1);
+        });
+    commands.insert_resource(LocalPlayer {
+        name: settings
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Explorer-{}", rand::thread_rng().gen_range(100..999))),
+        room_code: settings.room_code.clone(),
+        joined: false,
+        client_id,
+        rejected: None,
+        kicked: None,
+        phase: None,
+        session_token: None,
+        spectating: settings.spectate,
+        racers: Vec::new(),
+        spectator_count: 0,
+        locked: false,
+        banned: Vec::new(),
+        room_settings: RoomSettings::default(),
+        selected: None,
+        tick_rate: TICK_RATE,
+    });
+}
+
+/// Insert a fresh Renet client + transport, returning our client id. The
+/// UDP socket binds `local_addr` when it's still free, any port otherwise.
+fn connect(
+    commands: &mut Commands,
+    settings: &ClientSettings,
+    loopback: Option<&Loopback>,
+    local_addr: Option<LocalAddr>,
+) -> Result<u64, String> {
+    let client = RenetClient::new(connection_config());
+    if let Some(loopback) = loopback {
+        let mut transport = loopback.connect();
+        transport.set_conditions(settings.network);
+        let client_id = transport.client_id();
+        commands.insert_resource(client);
+        commands.insert_resource(LoopbackClient(transport));
+        return Ok(client_id);
+    }
+
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
 
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let any_port = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let socket = local_addr
+        .and_then(|LocalAddr(addr)| UdpSocket::bind(addr).ok())
+        .map_or_else(|| UdpSocket::bind(any_port), Ok)
+        .map_err(|err| format!("Could not open a socket: {err}"))?;
+    let bound = socket
+        .local_addr()
+        .map_err(|err| format!("Could not open a socket: {err}"))?;
+    if local_addr.is_some_and(|LocalAddr(addr)| addr != bound) && settings.connect_token.is_some() {
+        warn!("Reconnecting from a new address; the server will want a fresh connect token");
+    }
+    commands.insert_resource(LocalAddr(bound));
 
-    let auth = ClientAuthentication::Unsecure {
-        protocol_id: PROTOCOL_ID,
-        client_id,
-        server_addr: "127.0.0.1:5000".parse().unwrap(),
-        user_data: None,
-    };
+    let (client_id, auth) = client_authentication(settings)?;
 
-    let transport = NetcodeClientTransport::new(current_time, auth, socket).unwrap();
-    let client = RenetClient::new(ConnectionConfig::default());
+    let transport = NetcodeClientTransport::new(current_time, auth, socket)
+        .map_err(|err| format!("Could not start the netcode transport: {err}"))?;
 
     commands.insert_resource(client);
     commands.insert_resource(transport);
-    commands.insert_resource(LocalPlayer {
-        name: format!("Explorer-{}", rand::thread_rng().gen_range(100..999)),
-        room_code: None,
-        joined: false,
-        client_id,
-    });
+    Ok(client_id)
+}
+
+/// After the connection drops, keep reconnecting with a growing delay
+fn reconnect(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    settings: Res<Settings>,
+    loopback: Option<Res<Loopback>>,
+    local_addr: Option<Res<LocalAddr>>,
+    transport: Option<Res<NetcodeClientTransport>>,
+    client: Res<RenetClient>,
+    mut player: ResMut<LocalPlayer>,
+    mut reconnect: ResMut<Reconnect>,
+) {
+    if client.is_connected() {
+        if reconnect.attempts > 0 {
+            info!("Reconnected after {} attempt(s)", reconnect.attempts);
+            *reconnect = Reconnect::default();
+        }
+        return;
+    }
+    // A server speaking another protocol will never take us back, and
+    // the host didn't want us there
+    let incompatible = matches!(
+        player.rejected,
+        Some(JoinRejectReason::VersionMismatch { .. })
+    );
+    if !client.is_disconnected() || incompatible || player.kicked.is_some() {
+        return;
+    }
+
+    let now = time.elapsed_secs_f64();
+    let Some(due) = reconnect.next_attempt else {
+        let delay = RECONNECT_DELAY_SECONDS * f64::from(1 << reconnect.attempts.min(3));
+        warn!("Connection lost, reconnecting in {delay:.0}s");
+        reconnect.next_attempt = Some(now + delay);
+        // Close the old socket so the next attempt can bind its address
+        if transport.is_some() {
+            commands.remove_resource::<NetcodeClientTransport>();
+        }
+        return;
+    };
+    if now < due {
+        return;
+    }
+
+    reconnect.attempts += 1;
+    reconnect.next_attempt = None;
+    let local_addr = local_addr.as_deref().copied();
+    match connect(&mut commands, &settings, loopback.as_deref(), local_addr) {
+        Ok(client_id) => {
+            player.client_id = client_id;
+            player.joined = false;
+        }
+        Err(err) => warn!("{err}"),
+    }
+}
+
+/// Use the configured connect token if there is one, otherwise connect
+/// unsecurely with a random client id. The token is read on every attempt
+/// so a launcher can swap in a fresh one; reusing it only works from the
+/// same local address, which `connect` takes care of.
+fn client_authentication(settings: &ClientSettings) -> Result<(u64, ClientAuthentication), String> {
+    let Some(path) = &settings.connect_token else {
+        let client_id: u64 = rand::thread_rng().gen();
+        let auth = ClientAuthentication::Unsecure {
+            protocol_id: PROTOCOL_ID,
+            client_id,
+            server_addr: settings.server_addr,
+            user_data: None,
+        };
+        return Ok((client_id, auth));
+    };
+
+    let connect_token = File::open(path)
+        .map_err(|err| err.to_string())
+        .and_then(|mut file| ConnectToken::read(&mut file).map_err(|err| err.to_string()))
+        .map_err(|err| format!("Could not read connect token {}: {err}", path.display()))?;
+    Ok((
+        connect_token.client_id,
+        ClientAuthentication::Secure { connect_token },
+    ))
 }
 
-/// Once connected, send JoinRoom + auto-ready + auto-start
+/// Follow the server's tick rate once it tells us what it is
+fn sync_tick_rate(
+    player: Option<Res<LocalPlayer>>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut clock: ResMut<ServerClock>,
+    mut render_clock: ResMut<RenderClock>,
+    mut pending: ResMut<PendingInputs>,
+) {
+    let Some(player) = player else { return };
+    if clock.tick_rate() == player.tick_rate {
+        return;
+    }
+    info!("Server runs at {} ticks per second", player.tick_rate);
+    fixed_time.set_timestep_hz(f64::from(player.tick_rate));
+    **clock = ClockSync::new(player.tick_rate);
+    **render_clock = InterpolationClock::new(player.tick_rate);
+    **pending = InputHistory::new(player.tick_rate);
+}
+
+/// Once connected, send Hello + JoinRoom + auto-ready + auto-start
 fn poll_connection_status(mut client: ResMut<RenetClient>, mut player: ResMut<LocalPlayer>) {
     if client.is_disconnected() {
         return;
     }
 
     if client.is_connected() && !player.joined {
+        // 0) Introduce ourselves so the server can check compatibility
+        send(
+            &mut client,
+            &ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                build: BUILD.into(),
+            },
+        );
+
+        player.joined = true;
+
+        // After a dropped connection, take our old slot back
+        if let Some(session_token) = player.session_token {
+            send(&mut client, &ClientMessage::Resume { session_token });
+            return;
+        }
+
         // 1) Join the room so server knows our name
-        let join = ClientMessage::JoinRoom {
-            name: player.name.clone(),
-            room_code: player.room_code.clone(),
-        };
-        if let Ok(bytes) = bincode::serialize(&join) {
-            client.send_message(0, bytes);
+        send(
+            &mut client,
+            &ClientMessage::JoinRoom {
+                name: player.name.clone(),
+                room_code: player.room_code.clone(),
+                spectate: player.spectating,
+            },
+        );
+        if player.spectating {
+            return;
         }
 
         // 2) Auto-mark ourselves ready
-        if let Ok(bytes) = bincode::serialize(&ClientMessage::SetReady { ready: true }) {
-            client.send_message(0, bytes);
-        }
+        send(&mut client, &ClientMessage::SetReady { ready: true });
 
         // 3) Ask to start the race (only host’s request is honored)
-        if let Ok(bytes) = bincode::serialize(&ClientMessage::StartRace) {
-            client.send_message(0, bytes);
-        }
+        send(&mut client, &ClientMessage::StartRace);
+    }
+}
 
-        player.joined = true;
+/// Ping the server regularly to keep the clock estimate fresh
+fn send_pings(
+    time: Res<Time<Real>>,
+    mut client: ResMut<RenetClient>,
+    mut last_ping: Local<Option<f64>>,
+) {
+    if !client.is_connected() {
+        return;
+    }
+    let now = time.elapsed_secs_f64();
+    if last_ping.is_some_and(|last| now - last < PING_INTERVAL_SECONDS) {
+        return;
+    }
+    *last_ping = Some(now);
+    send(&mut client, &ClientMessage::Ping { client_time: now });
+}
+
+/// F9 switches the offline connection between a perfect network and the
+/// configured conditions, or a poor connection when none are configured
+fn toggle_network_conditions(
+    keyboard: Option<Res<ButtonInput<KeyCode>>>,
+    settings: Res<Settings>,
+    transport: Option<ResMut<LoopbackClient>>,
+) {
+    let (Some(keyboard), Some(mut transport)) = (keyboard, transport) else {
+        return;
+    };
+    if !keyboard.just_pressed(KeyCode::F9) {
+        return;
     }
+    let conditions = if !transport.conditions().is_ideal() {
+        NetworkConditions::default()
+    } else if settings.network.is_ideal() {
+        NetworkConditions::POOR
+    } else {
+        settings.network
+    };
+    info!("Simulated network: {conditions}");
+    transport.set_conditions(conditions);
 }
 
-/// Read keyboard and send InputFrame at fixed tick rate
+/// As host, Enter starts the race and L locks or unlocks the room. Tab
+/// selects a racer for H to hand the host role to, K to kick or B to ban.
+/// In the lobby T, G, [ and ] change the track, mode and speeds
+fn host_controls(
+    keyboard: Option<Res<ButtonInput<KeyCode>>>,
+    mut client: ResMut<RenetClient>,
+    mut player: ResMut<LocalPlayer>,
+) {
+    let Some(keyboard) = keyboard else { return };
+    if !client.is_connected() || !player.is_host() {
+        return;
+    }
+    if keyboard.just_pressed(KeyCode::Enter) {
+        send(&mut client, &ClientMessage::StartRace);
+    }
+    if keyboard.just_pressed(KeyCode::KeyL) {
+        let locked = !player.locked;
+        send(&mut client, &ClientMessage::LockRoom { locked });
+    }
+    if player.phase == Some(RoomPhase::Lobby) {
+        let mut settings = player.room_settings.clone();
+        if keyboard.just_pressed(KeyCode::KeyT) {
+            settings.track = settings.track.next();
+        }
+        if keyboard.just_pressed(KeyCode::KeyG) {
+            settings.mode = settings.mode.next();
+        }
+        for (key, scale) in [
+            (KeyCode::BracketLeft, SPEED_STEP.recip()),
+            (KeyCode::BracketRight, SPEED_STEP),
+        ] {
+            if keyboard.just_pressed(key) {
+                settings.base_speed *= scale;
+                settings.boost_speed *= scale;
+            }
+        }
+        // The server has the final say on max_racers
+        if settings != player.room_settings && settings.validate(settings.max_racers).is_ok() {
+            send(
+                &mut client,
+                &ClientMessage::SetRoomSettings {
+                    settings: Box::new(settings),
+                },
+            );
+        }
+    }
+    if keyboard.just_pressed(KeyCode::Tab) {
+        player.selected = player.next_racer(player.selected.unwrap_or(player.client_id));
+    }
+    let Some(id) = player.selected else { return };
+    if keyboard.just_pressed(KeyCode::KeyH) {
+        send(&mut client, &ClientMessage::TransferHost { to: id });
+    }
+    for (key, ban) in [(KeyCode::KeyK, false), (KeyCode::KeyB, true)] {
+        if keyboard.just_pressed(key) {
+            send(&mut client, &ClientMessage::Kick { id, ban });
+        }
+    }
+}
+
+/// Read keyboard and send InputFrame at fixed tick rate, stamped with the
+/// server tick it should be simulated on
+#[allow(clippy::too_many_arguments)]
 fn send_inputs(
     keyboard: Option<Res<ButtonInput<KeyCode>>>,
+    time: Res<Time<Real>>,
+    clock: Res<ServerClock>,
     mut client: ResMut<RenetClient>,
-    mut tick: ResMut<SnapshotTick>,
+    mut tick: ResMut<InputTick>,
+    mut pending: ResMut<PendingInputs>,
+    mut prediction: ResMut<Prediction>,
+    player: Res<LocalPlayer>,
+    avatars: Query<(&PlayerAvatar, &Transform, &Velocity)>,
 ) {
     let Some(keyboard) = keyboard else { return };
-    if !client.is_connected() {
+    if !client.is_connected() || player.spectating {
         return;
     }
 
-    tick.0 = tick.0.wrapping_add(1);
+    // Running too far ahead of the server, skip a frame to fall back
+    let Some(next) = clock.next_input_tick(time.elapsed_secs_f64(), tick.0) else {
+        return;
+    };
+    tick.0 = next;
 
     let input = InputFrame {
         tick: tick.0,
@@ -292,76 +797,245 @@ fn send_inputs(
         down: keyboard.pressed(KeyCode::KeyS) || keyboard.pressed(KeyCode::ArrowDown),
         left: keyboard.pressed(KeyCode::KeyA) || keyboard.pressed(KeyCode::ArrowLeft),
         right: keyboard.pressed(KeyCode::KeyD) || keyboard.pressed(KeyCode::ArrowRight),
+        ascend: keyboard.pressed(KeyCode::KeyE) || keyboard.pressed(KeyCode::PageUp),
+        descend: keyboard.pressed(KeyCode::KeyQ) || keyboard.pressed(KeyCode::PageDown),
         boost: keyboard.pressed(KeyCode::Space) || keyboard.pressed(KeyCode::ShiftLeft),
     };
 
-    if let Ok(bytes) = bincode::serialize(&ClientMessage::InputFrame(input)) {
-        client.send_message(0, bytes);
+    if player.simulating() {
+        let others: Vec<PlayerKinematics> = avatars
+            .iter()
+            .filter(|(avatar, _, _)| avatar.id != player.client_id)
+            .map(|(_, transform, velocity)| PlayerKinematics {
+                position: transform.translation,
+                velocity: **velocity,
+                stamina: 0.0,
+            })
+            .collect();
+        prediction.step(&input, player.tick_dt(), &player.room_settings, &others);
+    }
+    pending.record(input);
+    send(
+        &mut client,
+        &ClientMessage::Inputs(pending.latest(INPUT_REDUNDANCY)),
+    );
+}
+
+/// Serialize a message and send it on the channel it belongs to
+fn send(client: &mut RenetClient, msg: &ClientMessage) {
+    if let Ok(bytes) = bincode::serialize(msg) {
+        client.send_message(msg.channel(), bytes);
     }
 }
 
 /// Apply snapshots from server: spawn/update/despawn avatars
+#[allow(clippy::too_many_arguments)]
 fn apply_snapshots(
     mut commands: Commands,
+    time: Res<Time<Real>>,
     mut client: ResMut<RenetClient>,
+    mut player: ResMut<LocalPlayer>,
+    mut clock: ResMut<ServerClock>,
+    mut baselines: ResMut<SnapshotBaselines>,
+    mut pending: ResMut<PendingInputs>,
+    mut prediction: ResMut<Prediction>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut avatars: Query<
-        (
-            Entity,
-            &mut Transform,
-            &mut Velocity,
-            &PlayerAvatar,
-            &MeshMaterial3d<StandardMaterial>,
-        ),
-        Without<Camera>,
-    >,
+    mut avatars: Query<(
+        Entity,
+        &mut RemoteMotion,
+        &PlayerAvatar,
+        &MeshMaterial3d<StandardMaterial>,
+    )>,
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
     if !client.is_connected() {
         return;
     }
 
-    while let Some(message) = client.receive_message(0) {
+    let mut messages = Vec::new();
+    for channel in Channel::ALL {
+        while let Some(message) = client.receive_message(channel) {
+            messages.push(message);
+        }
+    }
+    let mut accepted = Vec::new();
+    let mut race_tick = 0;
+    for message in messages {
         if let Ok(msg) = bincode::deserialize::<ServerMessage>(&message) {
-            if let ServerMessage::Snapshot { entities, .. } = msg {
-                let live_ids: Vec<u64> = entities.iter().map(|e| e.id).collect();
-
-                // Despawn avatars that disappeared from snapshot
-                for (entity, _, _, avatar, _) in avatars.iter_mut() {
-                    if !live_ids.iter().any(|id| *id == avatar.id) {
-                        commands.entity(entity).despawn();
+            let (tick, last_input, snapshot_race_tick, entities) = match msg {
+                ServerMessage::Hello { build, .. } => {
+                    info!("Server is running build {build}");
+                    continue;
+                }
+                ServerMessage::Joined {
+                    room_code,
+                    session_token,
+                    tick_rate,
+                } => {
+                    player.tick_rate = tick_rate;
+                    player.room_code = Some(room_code);
+                    // Only racers have a slot to resume
+                    player.spectating = session_token.is_none();
+                    player.session_token = session_token;
+                    player.rejected = None;
+                    continue;
+                }
+                ServerMessage::Pong {
+                    client_time,
+                    server_tick,
+                } => {
+                    clock.observe(client_time, time.elapsed_secs_f64(), server_tick);
+                    continue;
+                }
+                ServerMessage::JoinRejected {
+                    reason: JoinRejectReason::SessionExpired,
+                } => {
+                    // Our slot is gone; join afresh on the next poll
+                    warn!("Session expired, joining again");
+                    player.session_token = None;
+                    player.joined = false;
+                    continue;
+                }
+                ServerMessage::JoinRejected { reason } => {
+                    warn!("Join rejected: {reason}");
+                    if matches!(reason, JoinRejectReason::VersionMismatch { .. }) {
+                        client.disconnect();
                     }
+                    player.rejected = Some(reason);
+                    continue;
                 }
-
-                // Update or spawn avatars
-                for snapshot in entities {
-                    let pos = Vec3::from(snapshot.position);
-                    let vel = Vec3::from(snapshot.velocity);
-                    let region = snapshot.region.clone();
-
-                    if let Some((_, mut transform, mut velocity, _, material)) = avatars
-                        .iter_mut()
-                        .find(|(_, _, _, avatar, _)| avatar.id == snapshot.id)
-                    {
-                        transform.translation = pos;
-                        **velocity = vel;
-                        if let Some(mat) = materials.get_mut(&material.0) {
-                            mat.base_color = color_for_region(region.clone());
-                            mat.emissive = color_for_region(region).into();
-                        }
-                    } else {
-                        spawn_avatar(
-                            &mut commands,
-                            &mut meshes,
-                            &mut materials,
-                            snapshot.id,
-                            pos,
-                            vel,
-                            region,
-                        );
+                ServerMessage::Kicked { reason } => {
+                    warn!("Removed from the room: {reason}");
+                    player.kicked = Some(reason);
+                    client.disconnect();
+                    continue;
+                }
+                ServerMessage::RoomState {
+                    room_code,
+                    players,
+                    spectators,
+                    state,
+                    locked,
+                    banned,
+                    settings,
+                } => {
+                    // Remember the room we ended up in so it can be shared
+                    if player.room_code.as_ref() != Some(&room_code) {
+                        player.room_code = Some(room_code);
+                    }
+                    player.phase = Some(state);
+                    if !players.iter().any(|p| Some(p.id) == player.selected) {
+                        player.selected = None;
                     }
+                    player.racers = players;
+                    player.spectator_count = spectators.len();
+                    player.locked = locked;
+                    player.banned = banned;
+                    player.room_settings = *settings;
+                    continue;
+                }
+                ServerMessage::Snapshot {
+                    tick,
+                    last_input,
+                    race_tick,
+                    entities,
+                } => (tick, last_input, race_tick, entities),
+                ServerMessage::SnapshotDelta {
+                    tick,
+                    last_input,
+                    race_tick,
+                    baseline,
+                    changed,
+                    removed,
+                } => {
+                    // Without the baseline we wait for the server to fall back
+                    // to a full snapshot of something we did acknowledge
+                    let Some(entities) = baselines
+                        .get(baseline)
+                        .and_then(|base| apply_delta(base, &changed, &removed))
+                    else {
+                        continue;
+                    };
+                    (tick, last_input, race_tick, entities)
                 }
+                _ => continue,
+            };
+            // Late or duplicated snapshots would drag avatars backwards
+            if baselines.newest().is_some_and(|newest| tick <= newest) {
+                continue;
             }
+            if let Some(last_input) = last_input {
+                pending.ack(last_input);
+            }
+            baselines.record(tick, entities.clone());
+            send(&mut client, &ClientMessage::SnapshotAck { tick });
+            race_tick = snapshot_race_tick;
+            accepted.push((tick, entities));
+        }
+    }
+
+    let Some((_, latest)) = accepted.last() else {
+        return;
+    };
+    if let Some(local) = latest.iter().find(|e| e.id == player.client_id) {
+        prediction.reconcile(
+            local.kinematics(),
+            pending.unacked(),
+            player.tick_dt(),
+            &player.room_settings,
+            race_tick,
+        );
+    }
+
+    // Despawn avatars that disappeared from the newest snapshot
+    for (entity, _, avatar, _) in avatars.iter() {
+        if !latest.iter().any(|e| e.id == avatar.id) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    // Feed every snapshot to the interpolation buffers, oldest first
+    let mut new_avatars: Vec<(EntitySnapshot, InterpolationBuffer)> = Vec::new();
+    for (tick, entities) in &accepted {
+        for snapshot in entities {
+            let sample = MotionSample {
+                tick: *tick,
+                position: snapshot.position(),
+                velocity: snapshot.velocity(),
+            };
+            let region = snapshot.region;
+
+            if let Some((_, mut motion, _, material)) = avatars
+                .iter_mut()
+                .find(|(_, _, avatar, _)| avatar.id == snapshot.id)
+            {
+                motion.push(sample);
+                if let Some(mat) = materials.get_mut(&material.0) {
+                    mat.base_color = color_for_region(region);
+                    mat.emissive = color_for_region(region).into();
+                }
+            } else if let Some((newest, motion)) =
+                new_avatars.iter_mut().find(|(e, _)| e.id == snapshot.id)
+            {
+                *newest = snapshot.clone();
+                motion.push(sample);
+            } else {
+                let mut motion = InterpolationBuffer::default();
+                motion.push(sample);
+                new_avatars.push((snapshot.clone(), motion));
+            }
+        }
+    }
+
+    for (snapshot, motion) in new_avatars {
+        if latest.iter().any(|e| e.id == snapshot.id) {
+            spawn_avatar(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &snapshot,
+                motion,
+            );
         }
     }
 }
@@ -370,42 +1044,56 @@ fn spawn_avatar(
     commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     materials: &mut ResMut<Assets<StandardMaterial>>,
-    id: u64,
-    pos: Vec3,
-    velocity: Vec3,
-    region: RegionId,
+    snapshot: &EntitySnapshot,
+    motion: InterpolationBuffer,
 ) {
     let body = meshes.add(Mesh::from(Capsule3d::new(
         PLAYER_RADIUS * 0.75,
         PLAYER_RADIUS * 2.0,
     )));
-    let mut material = StandardMaterial::from(color_for_region(region));
+    let mut material = StandardMaterial::from(color_for_region(snapshot.region));
     material.perceptual_roughness = 0.4;
     let material_handle = materials.add(material);
 
     commands.spawn((
         Mesh3d(body),
         MeshMaterial3d(material_handle),
-        Transform::from_translation(pos),
-        PlayerAvatar { id },
-        Velocity(velocity),
+        Transform::from_translation(snapshot.position()),
+        PlayerAvatar { id: snapshot.id },
+        Velocity(snapshot.velocity()),
+        RemoteMotion(motion),
     ));
 }
 
 /// Once we know our LocalPlayer, assign camera target id
-fn assign_follow_target(player: Option<Res<LocalPlayer>>, mut cameras: Query<&mut FollowCamera>) {
+fn assign_follow_target(
+    player: Option<Res<LocalPlayer>>,
+    mut cameras: Query<&mut FollowCamera>,
+    avatars: Query<(&PlayerAvatar, &Transform)>,
+) {
     let Some(player) = player else { return };
     let Ok(mut follow) = cameras.single_mut() else {
         return;
     };
-    if follow.target == 0 {
+    if !player.spectating {
+        // Our id changes whenever we reconnect
         follow.target = player.client_id;
+        return;
+    }
+    // Spectators watch whoever is in the lead
+    if let Some((leader, _)) = avatars
+        .iter()
+        .max_by(|(_, a), (_, b)| a.translation.x.total_cmp(&b.translation.x))
+    {
+        follow.target = leader.id;
     }
 }
 
-/// Camera follows target sperm; queries are made disjoint with `Without`
-/// to satisfy Bevy's borrowing rules.
+/// Camera follows target sperm from behind and above, staying inside the
+/// tube; queries are made disjoint with `Without` to satisfy Bevy's
+/// borrowing rules.
 fn camera_follow_target(
+    player: Option<Res<LocalPlayer>>,
     mut cameras: Query<(&mut Transform, &FollowCamera), Without<PlayerAvatar>>,
     avatars: Query<(&PlayerAvatar, &Transform, &Velocity), Without<FollowCamera>>,
 ) {
@@ -428,16 +1116,27 @@ fn camera_follow_target(
         Vec3::X
     };
 
-    let desired =
-        target_tf.translation - forward * follow.distance + Vec3::Y * follow.height;
+    // Trail behind on the level even when the target dives or climbs, so
+    // that looking at it never means looking straight up or down
+    let behind = Vec3::new(forward.x, 0.0, forward.z)
+        .try_normalize()
+        .unwrap_or(Vec3::X);
+    let desired = target_tf.translation - behind * follow.distance + Vec3::Y * follow.height;
+    let track = player.map_or(Track::default(), |p| p.room_settings.track);
+    let radius = tube_radius(region_for_position(desired, track)) - CAMERA_WALL_MARGIN;
+    let desired = clamp_to_radius(desired, radius);
 
     cam_tf.translation = cam_tf.translation.lerp(desired, 0.08);
     cam_tf.look_at(target_tf.translation + forward * 20.0, Vec3::Y);
 }
 
-/// Update HUD with connection + player count
+/// Update HUD with connection + room code + player count
 fn update_hud(
     client: Option<Res<RenetClient>>,
+    player: Option<Res<LocalPlayer>>,
+    clock: Res<ServerClock>,
+    reconnect: Res<Reconnect>,
+    loopback: Option<Res<LoopbackClient>>,
     avatars: Query<&PlayerAvatar>,
     mut hud_query: Query<&mut Text, With<HudText>>,
 ) {
@@ -445,25 +1144,98 @@ fn update_hud(
         return;
     };
 
-    let status = if let Some(client) = client {
+    let rejected = player.as_ref().and_then(|p| p.rejected.clone());
+    let kicked = player.as_ref().and_then(|p| p.kicked.clone());
+    let status = if let Some(reason) = kicked {
+        format!("Removed – {reason}")
+    } else if let Some(reason) = rejected {
+        format!("Rejected – {reason}")
+    } else if let Some(client) = client {
         if client.is_connected() {
-            "Connected"
+            "Connected".into()
+        } else if reconnect.next_attempt.is_some() {
+            "Connection lost, retrying soon".into()
+        } else if reconnect.attempts > 0 {
+            format!("Reconnecting (attempt {})...", reconnect.attempts)
         } else if client.is_disconnected() {
-            "Disconnected"
+            "Disconnected".into()
         } else {
-            "Connecting..."
+            "Connecting...".into()
         }
     } else {
-        "No client"
+        "No client".into()
     };
 
+    let ping = clock
+        .rtt()
+        .map(|rtt| format!("{:.0} ms", rtt * 1000.0))
+        .unwrap_or_else(|| "-".into());
     let count = avatars.iter().count();
+    let spectators = player.as_ref().map_or(0, |p| p.spectator_count);
+    let controls = if player.as_ref().is_some_and(|p| p.spectating) {
+        "Spectating: the camera follows the leader"
+    } else if player
+        .as_ref()
+        .is_some_and(|p| p.is_host() && p.phase == Some(RoomPhase::Lobby))
+    {
+        "Host: Enter to start, L to lock, T/G/[/] to change track, mode and speed, \
+         Tab to select a racer to pass host (H), kick (K) or ban (B)"
+    } else {
+        "Controls: WASD / Arrows to steer, E / Q to rise and dive, Space or Left Shift to boost"
+    };
+    let host = player
+        .as_ref()
+        .and_then(|p| {
+            let host = p.racers.iter().find(|r| r.is_host)?;
+            let you = if host.id == p.client_id { " (you)" } else { "" };
+            Some(format!("{}{you}", host.name))
+        })
+        .unwrap_or_else(|| "-".into());
+    let selected = player
+        .as_ref()
+        .filter(|p| p.is_host())
+        .and_then(|p| {
+            let racer = p.racers.iter().find(|r| Some(r.id) == p.selected)?;
+            Some(format!("Selected: {}\n", racer.name))
+        })
+        .unwrap_or_default();
+    let banned = player
+        .as_ref()
+        .filter(|p| !p.banned.is_empty())
+        .map(|p| format!("Banned: {}\n", p.banned.join(", ")))
+        .unwrap_or_default();
+    let race = player
+        .as_ref()
+        .map(|p| {
+            let s = &p.room_settings;
+            format!(
+                "Race: {}, {}, speed {:.0}/{:.0}, up to {} racers\n",
+                s.track, s.mode, s.base_speed, s.boost_speed, s.max_racers
+            )
+        })
+        .unwrap_or_default();
+    let locked = if player.as_ref().is_some_and(|p| p.locked) {
+        " (locked)"
+    } else {
+        ""
+    };
+    let room = player
+        .and_then(|p| p.room_code.clone())
+        .unwrap_or_else(|| "-".into());
+    // Only the offline connection can be impaired
+    let network = loopback
+        .map(|transport| format!("Network: {} (F9)\n", transport.conditions()))
+        .unwrap_or_default();
 
     *text = Text::new(format!(
         "Odyssey: Race to the Egg\n\
          Status: {status}\n\
+         Room: {room}{locked}\n\
+         Host: {host}\n\
+         Ping: {ping}\n\
          Players seen: {count}\n\
-         Controls: WASD / Arrows to steer, Space or Left Shift to boost"
+         Spectators: {spectators}\n\
+         {race}{selected}{banned}{network}{controls}"
     ));
 }
 
@@ -0,0 +1,92 @@
+use bevy::prelude::*;
+use shared::*;
+
+use crate::{LocalPlayer, PlayerAvatar, Velocity};
+
+/// Disagreements smaller than this are ignored; quantization alone causes some
+const CORRECTION_EPSILON: f32 = 0.5;
+/// Disagreements larger than this are snapped rather than smoothed
+const CORRECTION_SNAP: f32 = 200.0;
+/// How quickly a visual correction fades out, per second
+const CORRECTION_DECAY: f32 = 10.0;
+
+/// Locally predicted state of our own avatar, running ahead of the server
+#[derive(Resource, Default)]
+pub struct Prediction {
+    kin: Option<PlayerKinematics>,
+    /// Room race tick the next predicted input runs on
+    race_tick: u32,
+    /// Visual offset left by the last correction, decayed to zero over time
+    correction: Vec3,
+}
+
+impl Prediction {
+    /// Advance the prediction by one input as the server will, bumping
+    /// into `others` where we see them now. The server bumps against where
+    /// they really are, so that part is only approximate
+    pub fn step(
+        &mut self,
+        input: &InputFrame,
+        dt: f32,
+        settings: &RoomSettings,
+        others: &[PlayerKinematics],
+    ) {
+        if let Some(kin) = self.kin.take() {
+            let kin = simulate_tick(kin, input, dt, settings, self.race_tick);
+            let mut bodies = vec![kin];
+            bodies.extend_from_slice(others);
+            resolve_collisions(&mut bodies, settings);
+            self.kin = bodies.into_iter().next();
+        }
+        self.race_tick = self.race_tick.wrapping_add(1);
+    }
+
+    /// Rebuild the prediction from the server's state plus every input the
+    /// server hasn't simulated yet, the first of them on `race_tick`,
+    /// smoothing over any visible jump
+    pub fn reconcile<'a>(
+        &mut self,
+        authoritative: PlayerKinematics,
+        unacked: impl IntoIterator<Item = &'a InputFrame>,
+        dt: f32,
+        settings: &RoomSettings,
+        race_tick: u32,
+    ) {
+        let unacked: Vec<&InputFrame> = unacked.into_iter().collect();
+        self.race_tick = race_tick.wrapping_add(unacked.len() as u32);
+        let replayed = replay_inputs(authoritative, unacked, dt, settings, race_tick);
+        if let Some(predicted) = &self.kin {
+            let error = predicted.position - replayed.position;
+            if error.length() < CORRECTION_EPSILON {
+                return;
+            }
+            if error.length() > CORRECTION_SNAP {
+                self.correction = Vec3::ZERO;
+            } else {
+                self.correction += error;
+            }
+        }
+        self.kin = Some(replayed);
+    }
+}
+
+/// Place our own avatar at the predicted position instead of the last snapshot
+pub fn render_prediction(
+    time: Res<Time>,
+    player: Option<Res<LocalPlayer>>,
+    mut prediction: ResMut<Prediction>,
+    mut avatars: Query<(&PlayerAvatar, &mut Transform, &mut Velocity)>,
+) {
+    let Some(player) = player else { return };
+    let decay = (-CORRECTION_DECAY * time.delta_secs()).exp();
+    prediction.correction *= decay;
+
+    let Some(kin) = &prediction.kin else { return };
+    if let Some((_, mut transform, mut velocity)) = avatars
+        .iter_mut()
+        .find(|(avatar, _, _)| avatar.id == player.client_id)
+    {
+        transform.translation = kin.position + prediction.correction;
+        **velocity = kin.velocity;
+    }
+}
@@ -0,0 +1,69 @@
+//! Issues netcode connect tokens for a server running with `auth.secure`,
+//! using the key from `ODYSSEY_PRIVATE_KEY`.
+//!
+//! Usage: `issue_token <player-name> [server-addr] [out-file]`
+//!        `issue_token --new-key`
+
+use std::{fs::File, net::SocketAddr, time::SystemTime};
+
+use bevy_renet::netcode::ConnectToken;
+use rand::Rng;
+use shared::*;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--new-key") {
+        let key: [u8; PRIVATE_KEY_BYTES] = rand::thread_rng().gen();
+        println!("{}", private_key_to_hex(&key));
+        return;
+    }
+
+    let Some(name) = args.first() else {
+        fail("usage: issue_token <player-name> [server-addr] [out-file] | --new-key");
+    };
+    // The server would turn the name down when the token is used
+    let name =
+        validate_name(name).unwrap_or_else(|| fail(&JoinRejectReason::InvalidName.to_string()));
+    let server_addr: SocketAddr = args
+        .get(1)
+        .map(|addr| addr.parse())
+        .unwrap_or(Ok(ClientSettings::default().server_addr))
+        .unwrap_or_else(|err| fail(&format!("invalid server address: {err}")));
+    let out_path = args
+        .get(2)
+        .cloned()
+        .unwrap_or_else(|| format!("{name}.token"));
+
+    let hex = std::env::var(PRIVATE_KEY_ENV)
+        .unwrap_or_else(|_| fail(&format!("{PRIVATE_KEY_ENV} is not set")));
+    let private_key = parse_private_key(&hex).unwrap_or_else(|err| fail(&err.to_string()));
+    let user_data = encode_user_data(&name).unwrap_or_else(|err| fail(&err.to_string()));
+
+    let client_id: u64 = rand::thread_rng().gen();
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let token = ConnectToken::generate(
+        current_time,
+        PROTOCOL_ID,
+        TOKEN_EXPIRE_SECONDS,
+        client_id,
+        TOKEN_TIMEOUT_SECONDS,
+        vec![server_addr],
+        Some(&user_data),
+        &private_key,
+    )
+    .unwrap_or_else(|err| fail(&format!("could not generate token: {err}")));
+
+    let mut file = File::create(&out_path)
+        .unwrap_or_else(|err| fail(&format!("could not create {out_path}: {err}")));
+    token
+        .write(&mut file)
+        .unwrap_or_else(|err| fail(&format!("could not write {out_path}: {err}")));
+    println!("Wrote token for {name} (client {client_id}) to {out_path}");
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
@@ -0,0 +1,1305 @@
+//! Odyssey race server: rooms, the authoritative simulation and snapshot
+//! broadcast, independent of the transport clients connect over.
+
+pub mod loopback;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+
+use bevy::prelude::*;
+use bevy_renet::netcode::NetcodeServerTransport;
+use bevy_renet::renet::{RenetServer, ServerEvent};
+use bevy_renet::RenetServerPlugin;
+use rand::Rng;
+use shared::*;
+
+/// How long a racer's slot is held after their connection drops.
+const RECONNECT_GRACE_SECONDS: u32 = 30;
+/// How long a kicked or turned away client has to read why before the
+/// server hangs up.
+const KICK_NOTICE_SECONDS: f64 = 1.0;
+
+/// Hosts rooms for clients of whichever transport is added alongside it:
+/// `NetcodeServerPlugin` for UDP, or `LoopbackServerPlugin` in-process.
+pub struct ServerPlugin {
+    pub settings: ServerSettings,
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = self.settings.clone();
+        app.add_plugins(RenetServerPlugin)
+            .insert_resource(new_server())
+            .insert_resource(Time::<Fixed>::from_hz(f64::from(settings.tick_rate)))
+            .insert_resource(Settings(settings))
+            .insert_resource(Rooms::default())
+            .insert_resource(Connections::default())
+            .insert_resource(ServerTick::default())
+            .add_systems(
+                Update,
+                (
+                    handle_events,
+                    network_receive_system,
+                    disconnect_kicked,
+                    broadcast_room_state,
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    apply_inputs,
+                    physics_step,
+                    expire_held_slots,
+                    race_state_system,
+                    snapshot_broadcast_system,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Validated settings the server was started with.
+#[derive(Resource, Deref)]
+struct Settings(ServerSettings);
+
+/// Every room hosted by this process, plus which room each client joined.
+#[derive(Resource, Default)]
+struct Rooms {
+    rooms: HashMap<String, Room>,
+    membership: HashMap<u64, String>,
+}
+
+/// Server tick, advanced every fixed step and shared by every room so that
+/// clients can sync to a single timeline.
+#[derive(Resource, Default)]
+struct ServerTick(u32);
+
+/// Version handshake progress and abuse tracking of every connected client.
+#[derive(Resource, Default)]
+struct Connections {
+    greeted: HashSet<u64>,
+    rejected: HashSet<u64>,
+    limits: HashMap<u64, ClientLimits>,
+    /// Kicked or turned away clients still connected, with when they were
+    /// told.
+    kicked: HashMap<u64, f64>,
+}
+
+#[derive(Debug)]
+struct ClientLimits {
+    messages: RateLimiter,
+    strikes: u32,
+    /// When the last strike was given, for forgiving old ones.
+    struck_at: Option<f64>,
+    /// When a strike was last given for flooding, so a single flood only
+    /// costs one per second.
+    throttled_at: Option<f64>,
+}
+
+#[derive(Debug)]
+struct Room {
+    code: String,
+    players: HashMap<u64, PlayerState>,
+    spectators: HashMap<u64, Spectator>,
+    phase: RoomPhase,
+    settings: RoomSettings,
+    /// What is left of the current countdown.
+    countdown: u32,
+    race_start_tick: u32,
+    /// Ticks simulated since the countdown began, timing the environment.
+    race_tick: u32,
+    /// Recent snapshots by tick, used as delta baselines.
+    snapshots: VecDeque<(u32, Vec<EntitySnapshot>)>,
+    /// Last `RoomState` payload sent, so unchanged lobbies aren't resent.
+    last_room_state: Vec<u8>,
+    /// Racers that have joined so far, numbering them in order of arrival.
+    joins: u64,
+    /// Turns away new racers and spectators.
+    locked: bool,
+    bans: Vec<Ban>,
+}
+
+/// Someone the host banned, matched by client id or identity so a fresh
+/// connection from the same account stays out too.
+#[derive(Debug)]
+struct Ban {
+    client_id: u64,
+    identity: Option<Identity>,
+    name: String,
+}
+
+/// Who is behind a connection, as far as the transport can vouch for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Identity {
+    /// Connect token user data, only trusted from a secure server.
+    Token([u8; USER_DATA_BYTES]),
+    /// Address an unsecure client connects from.
+    Addr(IpAddr),
+}
+
+/// Identifies `client_id`. Unsecure clients write their own user data, so
+/// there only their address says anything.
+fn identify(
+    transport: Option<&NetcodeServerTransport>,
+    secure: bool,
+    client_id: u64,
+) -> Option<Identity> {
+    let transport = transport?;
+    if secure {
+        transport.user_data(client_id).map(Identity::Token)
+    } else {
+        let addr = transport.client_addr(client_id)?;
+        Some(Identity::Addr(addr.ip()))
+    }
+}
+
+#[derive(Debug)]
+struct PlayerState {
+    name: String,
+    ready: bool,
+    is_host: bool,
+    /// Position in the room's join order; the earliest connected racer
+    /// inherits the host role.
+    join_order: u64,
+    kin: PlayerKinematics,
+    inputs: InputBuffer,
+    /// Ticks from race start to reaching the ampulla.
+    finished_tick: Option<u32>,
+    acked_snapshot: Option<u32>,
+    /// Secret handed to the client for resuming this slot.
+    session_token: u64,
+    /// Server tick the connection dropped at, while the slot is held.
+    disconnected_at: Option<u32>,
+    /// Kept so a racer can be banned while their slot is held.
+    identity: Option<Identity>,
+}
+
+/// Watches a room's race without taking part in it.
+#[derive(Debug)]
+struct Spectator {
+    name: String,
+    acked_snapshot: Option<u32>,
+    identity: Option<Identity>,
+}
+
+fn new_server() -> RenetServer {
+    RenetServer::new(connection_config())
+}
+
+/// Sends `msg` on its channel to each of `clients`.
+fn send_to<'a>(
+    server: &mut RenetServer,
+    clients: impl IntoIterator<Item = &'a u64>,
+    msg: &ServerMessage,
+) {
+    let payload = bincode::serialize(msg).unwrap();
+    for client_id in clients {
+        server.send_message(*client_id, msg.channel(), payload.clone());
+    }
+}
+
+impl Room {
+    fn new(code: String, settings: RoomSettings) -> Self {
+        Self {
+            code,
+            players: HashMap::new(),
+            spectators: HashMap::new(),
+            phase: RoomPhase::Lobby,
+            countdown: settings.countdown_ms,
+            settings,
+            race_start_tick: 0,
+            race_tick: 0,
+            snapshots: VecDeque::with_capacity(SNAPSHOT_HISTORY),
+            last_room_state: Vec::new(),
+            joins: 0,
+            locked: false,
+            bans: Vec::new(),
+        }
+    }
+
+    fn start_countdown(&mut self) {
+        self.phase = RoomPhase::Countdown;
+        self.countdown = self.settings.countdown_ms;
+        self.race_tick = 0;
+    }
+
+    /// Handles messages from a client that already joined this room.
+    fn handle_message(&mut self, client_id: u64, msg: ClientMessage) {
+        match msg {
+            ClientMessage::Hello { .. }
+            | ClientMessage::JoinRoom { .. }
+            | ClientMessage::Ping { .. }
+            | ClientMessage::Resume { .. }
+            | ClientMessage::Kick { .. }
+            | ClientMessage::SetRoomSettings { .. } => {}
+            ClientMessage::SetReady { ready } => {
+                // Spectators have no say in when the race starts
+                let Some(player) = self.players.get_mut(&client_id) else {
+                    return;
+                };
+                player.ready = ready;
+                if self.players.values().all(|p| p.ready) && matches!(self.phase, RoomPhase::Lobby)
+                {
+                    self.start_countdown();
+                }
+            }
+            ClientMessage::Inputs(frames) => {
+                if let Some(player) = self.players.get_mut(&client_id) {
+                    // Redundant copies of frames already queued are dropped here
+                    for frame in frames {
+                        player.inputs.push(frame);
+                    }
+                }
+            }
+            ClientMessage::StartRace => {
+                if let Some(player) = self.players.get(&client_id) {
+                    if player.is_host && matches!(self.phase, RoomPhase::Lobby) {
+                        self.start_countdown();
+                    }
+                }
+            }
+            ClientMessage::TransferHost { to } => {
+                let is_host = self.players.get(&client_id).is_some_and(|p| p.is_host);
+                let to_connected = self
+                    .players
+                    .get(&to)
+                    .is_some_and(|p| p.disconnected_at.is_none());
+                if !is_host || !to_connected {
+                    warn!(
+                        "Client {client_id} can't make {to} host of room {}",
+                        self.code
+                    );
+                    return;
+                }
+                self.set_host(to);
+                info!(
+                    "Client {client_id} handed host of room {} to {to}",
+                    self.code
+                );
+            }
+            ClientMessage::LockRoom { locked } => {
+                if !self.players.get(&client_id).is_some_and(|p| p.is_host) {
+                    warn!("Client {client_id} can't lock room {}", self.code);
+                    return;
+                }
+                self.locked = locked;
+                let verb = if locked { "locked" } else { "unlocked" };
+                info!("Client {client_id} {verb} room {}", self.code);
+            }
+            ClientMessage::SnapshotAck { tick } => {
+                if let Some(player) = self.players.get_mut(&client_id) {
+                    player.acked_snapshot = player.acked_snapshot.max(Some(tick));
+                }
+                if let Some(spectator) = self.spectators.get_mut(&client_id) {
+                    spectator.acked_snapshot = spectator.acked_snapshot.max(Some(tick));
+                }
+            }
+        }
+    }
+
+    /// Racers whose connection is currently up, and spectators.
+    fn connected_clients(&self) -> impl Iterator<Item = &u64> {
+        self.players
+            .iter()
+            .filter(|(_, p)| p.disconnected_at.is_none())
+            .map(|(id, _)| id)
+            .chain(self.spectators.keys())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.players.is_empty() && self.spectators.is_empty()
+    }
+
+    fn set_host(&mut self, host: u64) {
+        for (id, player) in self.players.iter_mut() {
+            player.is_host = *id == host;
+        }
+    }
+
+    /// Keeps the host role with a connected racer: the current host while
+    /// they stay, otherwise whoever has been in the room longest.
+    fn migrate_host(&mut self) {
+        let connected = |p: &PlayerState| p.disconnected_at.is_none();
+        if self.players.values().any(|p| p.is_host && connected(p)) {
+            return;
+        }
+        let Some(host) = self
+            .players
+            .iter()
+            .filter(|(_, p)| connected(p))
+            .min_by_key(|(_, p)| p.join_order)
+            .map(|(id, _)| *id)
+        else {
+            return;
+        };
+        self.set_host(host);
+        info!("Client {host} is now host of room {}", self.code);
+    }
+
+    /// Removes `target` on the host's say, and with `ban` keeps them out
+    /// for the room's lifetime. A held slot goes too, so its session token
+    /// no longer resumes anything. Returns whether they were removed.
+    fn kick(&mut self, host: u64, target: u64, ban: bool) -> bool {
+        let is_host = self.players.get(&host).is_some_and(|p| p.is_host);
+        let found = match (self.players.get(&target), self.spectators.get(&target)) {
+            (Some(player), _) => Some((player.name.clone(), player.identity)),
+            (None, Some(spectator)) => Some((spectator.name.clone(), spectator.identity)),
+            (None, None) => None,
+        };
+        let (true, Some((name, identity))) = (is_host && target != host, found) else {
+            warn!("Client {host} can't kick {target} from room {}", self.code);
+            return false;
+        };
+        self.players.remove(&target);
+        self.spectators.remove(&target);
+        if ban {
+            self.bans.push(Ban {
+                client_id: target,
+                identity,
+                name,
+            });
+        }
+        let verb = if ban { "banned" } else { "kicked" };
+        info!("Client {host} {verb} {target} from room {}", self.code);
+        true
+    }
+
+    /// Replaces the race settings on the host's say, while in the lobby.
+    /// `max_players` is the most racers the server allows in a room.
+    fn set_settings(&mut self, host: u64, settings: RoomSettings, max_players: usize) {
+        let is_host = self.players.get(&host).is_some_and(|p| p.is_host);
+        if !is_host || self.phase != RoomPhase::Lobby {
+            warn!("Client {host} can't change settings of room {}", self.code);
+            return;
+        }
+        if let Err(err) = settings.validate(max_players) {
+            warn!(
+                "Client {host} sent bad settings for room {}: {err}",
+                self.code
+            );
+            return;
+        }
+        if settings.max_racers < self.players.len() {
+            warn!(
+                "Client {host} can't make room {} smaller than its {} racers",
+                self.code,
+                self.players.len()
+            );
+            return;
+        }
+        info!(
+            "Client {host} changed settings of room {}: {settings:?}",
+            self.code
+        );
+        self.settings = settings;
+    }
+
+    fn is_banned(&self, client_id: u64, identity: Option<Identity>) -> bool {
+        self.bans.iter().any(|ban| {
+            ban.client_id == client_id || (identity.is_some() && ban.identity == identity)
+        })
+    }
+
+    /// A new racer on the first starting grid spot nobody is on. Racers
+    /// only join in the lobby, where nobody has moved off their spot yet.
+    fn spawn_racer(&self) -> PlayerKinematics {
+        let spot = (0..)
+            .map(start_grid_position)
+            .find(|spot| self.players.values().all(|p| p.kin.position != *spot))
+            .expect("more grid spots than racers");
+        PlayerKinematics::spawn(spot)
+    }
+
+    /// Pushes overlapping racers apart, in id order so that every run of
+    /// the same inputs bumps the same way.
+    fn bump_players(&mut self) {
+        let mut ids: Vec<u64> = self.players.keys().copied().collect();
+        ids.sort_unstable();
+        let mut bodies: Vec<PlayerKinematics> =
+            ids.iter().map(|id| self.players[id].kin.clone()).collect();
+        resolve_collisions(&mut bodies, &self.settings);
+        for (id, kin) in ids.iter().zip(bodies) {
+            if let Some(player) = self.players.get_mut(id) {
+                player.kin = kin;
+            }
+        }
+    }
+
+    /// Whether the race is decided, going by the room's game mode.
+    fn race_over(&self) -> bool {
+        let finished = |p: &PlayerState| p.finished_tick.is_some();
+        let someone_finished = self.players.values().any(finished);
+        match self.settings.mode {
+            GameMode::FirstToFinish => someone_finished,
+            GameMode::AllFinish => {
+                someone_finished
+                    && self
+                        .players
+                        .values()
+                        .filter(|p| p.disconnected_at.is_none())
+                        .all(finished)
+            }
+        }
+    }
+
+    fn leaderboard(&self) -> Vec<LeaderboardEntry> {
+        let mut finished: Vec<_> = self
+            .players
+            .values()
+            .filter_map(|p| p.finished_tick.map(|ticks| (p.name.clone(), ticks)))
+            .collect();
+        finished.sort_by_key(|(_, ticks)| *ticks);
+        finished
+            .into_iter()
+            .map(|(name, ticks)| LeaderboardEntry { name, ticks })
+            .collect()
+    }
+}
+
+impl Rooms {
+    fn room_of_mut(&mut self, client_id: u64) -> Option<&mut Room> {
+        let code = self.membership.get(&client_id)?;
+        self.rooms.get_mut(code)
+    }
+
+    /// Places a client in the room matching `room_code`, or in a fresh room
+    /// when no code is given. Late joiners become spectators. Returns the
+    /// code of the room joined and, for racers, their session token.
+    fn join(
+        &mut self,
+        client_id: u64,
+        identity: Option<Identity>,
+        name: String,
+        room_code: Option<String>,
+        spectate: bool,
+        settings: &ServerSettings,
+    ) -> Result<(String, Option<u64>), JoinRejectReason> {
+        if let Some(code) = self.membership.get(&client_id).cloned() {
+            let room = self
+                .room_of_mut(client_id)
+                .ok_or(JoinRejectReason::BadRoomCode)?;
+            if let Some(spectator) = room.spectators.get_mut(&client_id) {
+                spectator.name = name;
+                return Ok((code, None));
+            }
+            let player = room
+                .players
+                .get_mut(&client_id)
+                .ok_or(JoinRejectReason::BadRoomCode)?;
+            player.name = name;
+            return Ok((code, Some(player.session_token)));
+        }
+
+        let code = match room_code.map(|c| c.trim().to_ascii_uppercase()) {
+            Some(code) => {
+                let room = self
+                    .rooms
+                    .get_mut(&code)
+                    .ok_or(JoinRejectReason::BadRoomCode)?;
+                if room.is_banned(client_id, identity) {
+                    return Err(JoinRejectReason::Banned);
+                }
+                if room.locked {
+                    return Err(JoinRejectReason::RoomLocked);
+                }
+                // Racers turning up mid-race watch until the next one
+                if spectate || room.phase != RoomPhase::Lobby {
+                    if room.spectators.len() >= MAX_SPECTATORS {
+                        return Err(JoinRejectReason::RoomFull);
+                    }
+                    room.spectators.insert(
+                        client_id,
+                        Spectator {
+                            name,
+                            acked_snapshot: None,
+                            identity,
+                        },
+                    );
+                    self.membership.insert(client_id, code.clone());
+                    return Ok((code, None));
+                }
+                if room.players.len() >= room.settings.max_racers {
+                    return Err(JoinRejectReason::RoomFull);
+                }
+                code
+            }
+            // There is nothing to watch in a room of our own
+            None if spectate => return Err(JoinRejectReason::BadRoomCode),
+            None => {
+                if self.rooms.len() >= settings.max_rooms {
+                    return Err(JoinRejectReason::ServerFull);
+                }
+                let code = self.unused_room_code();
+                let room = Room::new(code.clone(), settings.room_settings());
+                self.rooms.insert(code.clone(), room);
+                info!("Room {code} created");
+                code
+            }
+        };
+
+        let room = self
+            .rooms
+            .get_mut(&code)
+            .ok_or(JoinRejectReason::BadRoomCode)?;
+        let session_token = rand::thread_rng().gen();
+        let kin = room.spawn_racer();
+        room.joins += 1;
+        room.players.insert(
+            client_id,
+            PlayerState {
+                name,
+                ready: false,
+                is_host: false,
+                join_order: room.joins,
+                kin,
+                inputs: InputBuffer::new(settings.input_buffer),
+                finished_tick: None,
+                acked_snapshot: None,
+                session_token,
+                disconnected_at: None,
+                identity,
+            },
+        );
+        room.migrate_host();
+        self.membership.insert(client_id, code.clone());
+        Ok((code, Some(session_token)))
+    }
+
+    /// Hands the slot owning `session_token` to a reconnected client.
+    /// Returns the code of the room resumed.
+    fn resume(
+        &mut self,
+        client_id: u64,
+        session_token: u64,
+        identity: Option<Identity>,
+        input_buffer: InputBufferConfig,
+    ) -> Result<String, JoinRejectReason> {
+        let (code, old_id) = self
+            .rooms
+            .iter()
+            .find_map(|(code, room)| {
+                room.players
+                    .iter()
+                    .find(|(_, p)| p.session_token == session_token)
+                    .map(|(id, _)| (code.clone(), *id))
+            })
+            .ok_or(JoinRejectReason::SessionExpired)?;
+        // Secure clients reconnecting with a fresh token keep their id
+        if old_id != client_id {
+            // Whatever room this connection joined since is left behind
+            self.leave(client_id);
+            // The old connection may not have timed out yet
+            self.membership.remove(&old_id);
+        }
+        let room = self
+            .rooms
+            .get_mut(&code)
+            .ok_or(JoinRejectReason::SessionExpired)?;
+        if old_id != client_id {
+            let player = room
+                .players
+                .remove(&old_id)
+                .ok_or(JoinRejectReason::SessionExpired)?;
+            room.players.insert(client_id, player);
+        }
+        let player = room
+            .players
+            .get_mut(&client_id)
+            .ok_or(JoinRejectReason::SessionExpired)?;
+        player.disconnected_at = None;
+        player.identity = identity;
+        player.inputs = InputBuffer::new(input_buffer);
+        player.acked_snapshot = None;
+        room.migrate_host();
+        self.membership.insert(client_id, code.clone());
+        Ok(code)
+    }
+
+    /// Mid-race a dropped player's slot is held so they can resume it;
+    /// in the lobby they, like spectators, simply leave.
+    fn disconnect(&mut self, client_id: u64, tick: u32) {
+        let Some(room) = self.room_of_mut(client_id) else {
+            return;
+        };
+        if room.phase == RoomPhase::Lobby || room.spectators.contains_key(&client_id) {
+            self.leave(client_id);
+            return;
+        }
+        if let Some(player) = room.players.get_mut(&client_id) {
+            player.disconnected_at = Some(tick);
+        }
+        room.migrate_host();
+        self.membership.remove(&client_id);
+    }
+
+    fn leave(&mut self, client_id: u64) {
+        let Some(code) = self.membership.remove(&client_id) else {
+            return;
+        };
+        let Some(room) = self.rooms.get_mut(&code) else {
+            return;
+        };
+        room.players.remove(&client_id);
+        room.spectators.remove(&client_id);
+        if room.is_empty() {
+            self.rooms.remove(&code);
+            info!("Room {code} closed");
+        } else {
+            room.migrate_host();
+        }
+    }
+
+    fn unused_room_code(&self) -> String {
+        loop {
+            let code = random_room_code();
+            if !self.rooms.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+}
+
+fn handle_events(
+    mut events: MessageReader<ServerEvent>,
+    tick: Res<ServerTick>,
+    mut rooms: ResMut<Rooms>,
+    mut connections: ResMut<Connections>,
+) {
+    for event in events.read() {
+        match event {
+            ServerEvent::ClientConnected { client_id, .. } => {
+                info!("Client {client_id} connected");
+            }
+            ServerEvent::ClientDisconnected { client_id, .. } => {
+                rooms.disconnect(*client_id, tick.0);
+                connections.greeted.remove(client_id);
+                connections.rejected.remove(client_id);
+                connections.limits.remove(client_id);
+                connections.kicked.remove(client_id);
+                info!("Client {client_id} disconnected");
+            }
+        }
+    }
+}
+
+fn reject(
+    server: &mut RenetServer,
+    connections: &mut Connections,
+    client_id: u64,
+    reason: JoinRejectReason,
+    now: f64,
+) {
+    warn!("Rejecting client {client_id}: {reason}");
+    if matches!(reason, JoinRejectReason::VersionMismatch { .. }) {
+        // Nothing else it says can be trusted to decode, so once it has
+        // heard why, hang up
+        connections.rejected.insert(client_id);
+        connections.kicked.insert(client_id, now);
+    }
+    send_to(
+        server,
+        [&client_id],
+        &ServerMessage::JoinRejected { reason },
+    );
+}
+
+/// Hangs up on kicked and turned away clients once they have had time to
+/// hear why.
+fn disconnect_kicked(
+    mut server: ResMut<RenetServer>,
+    real_time: Res<Time<Real>>,
+    connections: Res<Connections>,
+) {
+    let now = real_time.elapsed_secs_f64();
+    for (client_id, at) in &connections.kicked {
+        if now - at >= KICK_NOTICE_SECONDS {
+            server.disconnect(*client_id);
+        }
+    }
+}
+
+/// Records a violation at `now`, disconnecting the client once it has too
+/// many. Strikes wear off over time, so rare slips never add up.
+fn strike(
+    server: &mut RenetServer,
+    connections: &mut Connections,
+    client_id: u64,
+    violation: Violation,
+    now: f64,
+) {
+    let Some(limits) = connections.limits.get_mut(&client_id) else {
+        return;
+    };
+    if let Some(at) = limits.struck_at {
+        let forgiven = ((now - at) / STRIKE_DECAY_SECONDS) as u32;
+        limits.strikes = limits.strikes.saturating_sub(forgiven);
+    }
+    limits.struck_at = Some(now);
+    limits.strikes += 1;
+    warn!(
+        "Client {client_id} strike {}/{MAX_STRIKES}: {violation}",
+        limits.strikes
+    );
+    if limits.strikes >= MAX_STRIKES {
+        warn!("Disconnecting client {client_id} for abuse");
+        // Nothing else it sends is worth reading
+        connections.rejected.insert(client_id);
+        server.disconnect(client_id);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn network_receive_system(
+    mut server: ResMut<RenetServer>,
+    transport: Option<Res<NetcodeServerTransport>>,
+    settings: Res<Settings>,
+    tick: Res<ServerTick>,
+    fixed_time: Res<Time<Fixed>>,
+    real_time: Res<Time<Real>>,
+    mut rooms: ResMut<Rooms>,
+    mut connections: ResMut<Connections>,
+) {
+    let now = real_time.elapsed_secs_f64();
+    for (client_id, channel) in server
+        .clients_id()
+        .into_iter()
+        .flat_map(|id| Channel::ALL.map(|channel| (id, channel)))
+    {
+        while let Some(message) = server.receive_message(client_id, channel) {
+            if connections.rejected.contains(&client_id) {
+                continue;
+            }
+            let limits = connections
+                .limits
+                .entry(client_id)
+                .or_insert_with(|| ClientLimits {
+                    messages: RateLimiter::new(settings.max_messages_per_second()),
+                    strikes: 0,
+                    struck_at: None,
+                    throttled_at: None,
+                });
+            if !limits.messages.allow(now) {
+                if limits.throttled_at.is_none_or(|at| now - at >= 1.0) {
+                    limits.throttled_at = Some(now);
+                    let violation = Violation::RateLimited(limits.messages.per_second());
+                    strike(&mut server, &mut connections, client_id, violation, now);
+                }
+                continue;
+            }
+            let greeted = connections.greeted.contains(&client_id);
+            // Unreliable inputs may overtake the Hello, but ordered traffic
+            // before it means the client predates the handshake
+            let skipped_handshake = !greeted && channel == Channel::Reliable;
+            let pre_handshake = JoinRejectReason::VersionMismatch {
+                server: PROTOCOL_VERSION,
+                client: 0,
+            };
+
+            let Ok(msg) = bincode::deserialize::<ClientMessage>(&message) else {
+                if skipped_handshake {
+                    reject(&mut server, &mut connections, client_id, pre_handshake, now);
+                } else if greeted {
+                    strike(
+                        &mut server,
+                        &mut connections,
+                        client_id,
+                        Violation::Malformed,
+                        now,
+                    );
+                }
+                continue;
+            };
+            match msg {
+                ClientMessage::Hello {
+                    protocol_version,
+                    build,
+                } => {
+                    if protocol_version != PROTOCOL_VERSION {
+                        let reason = JoinRejectReason::VersionMismatch {
+                            server: PROTOCOL_VERSION,
+                            client: protocol_version,
+                        };
+                        reject(&mut server, &mut connections, client_id, reason, now);
+                        continue;
+                    }
+                    info!("Client {client_id} running build {build}");
+                    connections.greeted.insert(client_id);
+                    let hello = ServerMessage::Hello {
+                        protocol_version: PROTOCOL_VERSION,
+                        build: BUILD.into(),
+                    };
+                    send_to(&mut server, [&client_id], &hello);
+                }
+                _ if skipped_handshake => {
+                    reject(&mut server, &mut connections, client_id, pre_handshake, now);
+                }
+                _ if !greeted => {}
+                ClientMessage::Ping { client_time } => {
+                    // Part way to the next fixed step, we're between ticks
+                    let pong = ServerMessage::Pong {
+                        client_time,
+                        server_tick: f64::from(tick.0) + fixed_time.overstep_fraction_f64(),
+                    };
+                    send_to(&mut server, [&client_id], &pong);
+                }
+                ClientMessage::JoinRoom {
+                    name,
+                    room_code,
+                    spectate,
+                } => {
+                    let identity = identify(transport.as_deref(), settings.auth.secure, client_id);
+                    // A secure connect token's name wins over whatever the
+                    // client claims
+                    let name = match &identity {
+                        Some(Identity::Token(data)) => decode_user_data(data).unwrap_or(name),
+                        _ => name,
+                    };
+                    let Some(name) = validate_name(&name) else {
+                        let reason = JoinRejectReason::InvalidName;
+                        reject(&mut server, &mut connections, client_id, reason, now);
+                        continue;
+                    };
+                    let joined =
+                        rooms.join(client_id, identity, name, room_code, spectate, &settings);
+                    match joined {
+                        Ok((room_code, session_token)) => {
+                            let role = match session_token {
+                                Some(_) => "racer",
+                                None => "spectator",
+                            };
+                            info!("Client {client_id} joined room {room_code} as {role}");
+                            let joined = ServerMessage::Joined {
+                                room_code,
+                                session_token,
+                                tick_rate: settings.tick_rate,
+                            };
+                            send_to(&mut server, [&client_id], &joined);
+                        }
+                        Err(reason) => {
+                            reject(&mut server, &mut connections, client_id, reason, now)
+                        }
+                    }
+                }
+                ClientMessage::Resume { session_token } => {
+                    let identity = identify(transport.as_deref(), settings.auth.secure, client_id);
+                    match rooms.resume(client_id, session_token, identity, settings.input_buffer) {
+                        Ok(room_code) => {
+                            info!("Client {client_id} resumed in room {room_code}");
+                            let joined = ServerMessage::Joined {
+                                room_code,
+                                session_token: Some(session_token),
+                                tick_rate: settings.tick_rate,
+                            };
+                            send_to(&mut server, [&client_id], &joined);
+                        }
+                        Err(reason) => {
+                            reject(&mut server, &mut connections, client_id, reason, now)
+                        }
+                    }
+                }
+                ClientMessage::Inputs(frames) => {
+                    let fresh = match validate_inputs(&frames, tick.0, settings.input_window()) {
+                        Ok([]) => continue,
+                        Ok(fresh) => fresh.to_vec(),
+                        Err(violation) => {
+                            strike(&mut server, &mut connections, client_id, violation, now);
+                            continue;
+                        }
+                    };
+                    if let Some(room) = rooms.room_of_mut(client_id) {
+                        room.handle_message(client_id, ClientMessage::Inputs(fresh));
+                    }
+                }
+                ClientMessage::Kick { id, ban } => {
+                    let Some(room) = rooms.room_of_mut(client_id) else {
+                        continue;
+                    };
+                    if !room.kick(client_id, id, ban) {
+                        continue;
+                    }
+                    // A held slot has no connection left to tell
+                    if rooms.membership.remove(&id).is_none() {
+                        continue;
+                    }
+                    let reason = if ban {
+                        KickReason::Banned
+                    } else {
+                        KickReason::Kicked
+                    };
+                    send_to(&mut server, [&id], &ServerMessage::Kicked { reason });
+                    // Nothing else it sends matters now
+                    connections.rejected.insert(id);
+                    connections.kicked.insert(id, now);
+                }
+                ClientMessage::SetRoomSettings {
+                    settings: room_settings,
+                } => {
+                    if let Some(room) = rooms.room_of_mut(client_id) {
+                        room.set_settings(client_id, *room_settings, settings.max_players);
+                    }
+                }
+                msg => {
+                    if let Some(room) = rooms.room_of_mut(client_id) {
+                        room.handle_message(client_id, msg);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Consumes exactly one buffered input per player per tick, so a player's
+/// movement doesn't depend on how their packets were spaced.
+fn apply_inputs(settings: Res<Settings>, mut rooms: ResMut<Rooms>) {
+    let dt = 1.0 / settings.tick_rate as f32;
+    for room in rooms.rooms.values_mut() {
+        let moving = matches!(room.phase, RoomPhase::Countdown | RoomPhase::Racing);
+        for player in room.players.values_mut() {
+            // Drain in every phase so the buffer stays aligned with the client
+            let input = player.inputs.pop();
+            if !moving {
+                continue;
+            }
+            player.kin = simulate_tick(
+                player.kin.clone(),
+                &input,
+                dt,
+                &room.settings,
+                room.race_tick,
+            );
+        }
+        if moving {
+            room.race_tick = room.race_tick.wrapping_add(1);
+        }
+    }
+}
+
+fn physics_step(settings: Res<Settings>, mut tick: ResMut<ServerTick>, mut rooms: ResMut<Rooms>) {
+    tick.0 = tick.0.wrapping_add(1);
+    for room in rooms.rooms.values_mut() {
+        if matches!(room.phase, RoomPhase::Countdown | RoomPhase::Racing) {
+            room.bump_players();
+        }
+        if matches!(room.phase, RoomPhase::Countdown) {
+            if room.countdown > 0 {
+                room.countdown = room.countdown.saturating_sub(1000 / settings.tick_rate);
+                if room.countdown == 0 {
+                    room.phase = RoomPhase::Racing;
+                    room.race_start_tick = tick.0;
+                }
+            }
+            continue;
+        }
+
+        if !matches!(room.phase, RoomPhase::Racing) {
+            continue;
+        }
+
+        let race_ticks = tick.0.wrapping_sub(room.race_start_tick);
+        for player in room.players.values_mut() {
+            if region_for_position(player.kin.position, room.settings.track) == RegionId::Ampulla
+                && player.finished_tick.is_none()
+            {
+                player.finished_tick = Some(race_ticks);
+            }
+        }
+    }
+}
+
+/// Frees slots whose owner didn't come back in time, and closes rooms left
+/// empty.
+fn expire_held_slots(settings: Res<Settings>, tick: Res<ServerTick>, mut rooms: ResMut<Rooms>) {
+    let grace = RECONNECT_GRACE_SECONDS * settings.tick_rate;
+    for room in rooms.rooms.values_mut() {
+        room.players.retain(|id, player| {
+            let expired = player
+                .disconnected_at
+                .is_some_and(|at| tick.0.wrapping_sub(at) >= grace);
+            if expired {
+                info!("Slot of client {id} in room {} expired", room.code);
+            }
+            !expired
+        });
+        room.migrate_host();
+    }
+    rooms.rooms.retain(|code, room| {
+        if room.is_empty() {
+            info!("Room {code} closed");
+        }
+        !room.is_empty()
+    });
+}
+
+fn race_state_system(mut server: ResMut<RenetServer>, mut rooms: ResMut<Rooms>) {
+    for room in rooms.rooms.values_mut() {
+        if matches!(room.phase, RoomPhase::Racing) && room.race_over() {
+            room.phase = RoomPhase::Finished;
+            info!("Room {} finished", room.code);
+
+            let msg = ServerMessage::RaceFinished {
+                leaderboard: room.leaderboard(),
+            };
+            send_to(&mut server, room.connected_clients(), &msg);
+        }
+    }
+}
+
+/// Sends each client the current snapshot as a delta against the newest
+/// snapshot it acknowledged, or in full when that baseline is unavailable.
+fn snapshot_broadcast_system(
+    mut server: ResMut<RenetServer>,
+    settings: Res<Settings>,
+    tick: Res<ServerTick>,
+    mut rooms: ResMut<Rooms>,
+) {
+    if !tick.0.is_multiple_of(settings.snapshot_interval()) {
+        return;
+    }
+    for room in rooms.rooms.values_mut() {
+        if !matches!(room.phase, RoomPhase::Racing | RoomPhase::Countdown) {
+            continue;
+        }
+
+        let entities: Vec<EntitySnapshot> = room
+            .players
+            .iter()
+            .map(|(id, player)| {
+                EntitySnapshot::new(
+                    *id,
+                    player.kin.position,
+                    player.kin.velocity,
+                    player.kin.stamina,
+                    region_for_position(player.kin.position, room.settings.track),
+                )
+            })
+            .collect();
+
+        // Spectators get the same snapshots, with no inputs to acknowledge
+        let racers = room
+            .players
+            .iter()
+            .filter(|(_, p)| p.disconnected_at.is_none())
+            .map(|(id, p)| (id, p.acked_snapshot, p.inputs.last_consumed()));
+        let spectators = room
+            .spectators
+            .iter()
+            .map(|(id, s)| (id, s.acked_snapshot, None));
+        for (client_id, acked_snapshot, last_input) in racers.chain(spectators) {
+            let baseline = acked_snapshot.and_then(|acked| {
+                room.snapshots
+                    .iter()
+                    .find(|(tick, _)| *tick == acked)
+                    .map(|(tick, baseline)| (*tick, baseline))
+            });
+            let msg = match baseline {
+                Some((baseline_tick, baseline)) => {
+                    let (changed, removed) = diff_snapshots(baseline, &entities);
+                    ServerMessage::SnapshotDelta {
+                        tick: tick.0,
+                        last_input,
+                        race_tick: room.race_tick,
+                        baseline: baseline_tick,
+                        changed,
+                        removed,
+                    }
+                }
+                None => ServerMessage::Snapshot {
+                    tick: tick.0,
+                    last_input,
+                    race_tick: room.race_tick,
+                    entities: entities.clone(),
+                },
+            };
+            send_to(&mut server, [client_id], &msg);
+        }
+
+        if room.snapshots.len() == SNAPSHOT_HISTORY {
+            room.snapshots.pop_front();
+        }
+        room.snapshots.push_back((tick.0, entities));
+    }
+}
+
+fn broadcast_room_state(mut server: ResMut<RenetServer>, mut rooms: ResMut<Rooms>) {
+    for room in rooms.rooms.values_mut() {
+        let msg = ServerMessage::RoomState {
+            room_code: room.code.clone(),
+            players: room
+                .players
+                .iter()
+                .map(|(id, p)| PlayerSummary {
+                    id: *id,
+                    name: p.name.clone(),
+                    ready: p.ready,
+                    is_host: p.is_host,
+                })
+                .collect(),
+            spectators: room
+                .spectators
+                .iter()
+                .map(|(id, s)| SpectatorSummary {
+                    id: *id,
+                    name: s.name.clone(),
+                })
+                .collect(),
+            state: room.phase.clone(),
+            locked: room.locked,
+            banned: room.bans.iter().map(|ban| ban.name.clone()).collect(),
+            settings: Box::new(room.settings.clone()),
+        };
+
+        let payload = bincode::serialize(&msg).unwrap();
+        if payload == room.last_room_state {
+            continue;
+        }
+        for client_id in room.connected_clients() {
+            server.send_message(*client_id, msg.channel(), payload.clone());
+        }
+        room.last_room_state = payload;
+    }
+}
+
+fn random_room_code() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ";
+    let mut rng = rand::thread_rng();
+    (0..4)
+        .map(|_| {
+            let idx = rng.gen_range(0..ALPHABET.len());
+            ALPHABET[idx] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use bevy::time::TimeUpdateStrategy;
+    use bevy_renet::netcode::{
+        ClientAuthentication, NetcodeClientTransport, NetcodeServerPlugin, ServerAuthentication,
+        ServerConfig,
+    };
+    use bevy_renet::renet::RenetClient;
+
+    use super::*;
+
+    fn now() -> Duration {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+    }
+
+    /// A server taking unsecure connections on a local UDP socket.
+    fn unsecure_server(settings: ServerSettings) -> (App, SocketAddr) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let config = ServerConfig {
+            current_time: now(),
+            max_clients: settings.max_clients(),
+            protocol_id: PROTOCOL_ID,
+            public_addresses: vec![addr],
+            authentication: ServerAuthentication::Unsecure,
+        };
+        let step = Duration::from_secs_f64(1.0 / f64::from(settings.tick_rate));
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(step))
+            .add_plugins(ServerPlugin { settings })
+            .add_plugins(NetcodeServerPlugin)
+            .insert_resource(NetcodeServerTransport::new(config, socket).unwrap());
+        (app, addr)
+    }
+
+    #[test]
+    fn banning_a_held_slot_keeps_its_racer_out() {
+        let settings = ServerSettings::default();
+        let mut rooms = Rooms::default();
+        let addr = |last| Some(Identity::Addr(IpAddr::from([203, 0, 113, last])));
+        let (code, _) = rooms
+            .join(1, addr(1), "Host".into(), None, false, &settings)
+            .unwrap();
+        let (_, session_token) = rooms
+            .join(
+                2,
+                addr(2),
+                "Guest".into(),
+                Some(code.clone()),
+                false,
+                &settings,
+            )
+            .unwrap();
+        rooms.rooms.get_mut(&code).unwrap().phase = RoomPhase::Racing;
+        rooms.disconnect(2, 100);
+
+        let room = rooms.rooms.get_mut(&code).unwrap();
+        assert!(room.kick(1, 2, true));
+        let resumed = rooms.resume(3, session_token.unwrap(), addr(2), settings.input_buffer);
+        assert_eq!(resumed, Err(JoinRejectReason::SessionExpired));
+        let rejoined = rooms.join(
+            3,
+            addr(2),
+            "Guest".into(),
+            Some(code.clone()),
+            true,
+            &settings,
+        );
+        assert_eq!(rejoined, Err(JoinRejectReason::Banned));
+        let other = rooms.join(4, addr(4), "Other".into(), Some(code), true, &settings);
+        assert!(other.is_ok());
+    }
+
+    #[test]
+    fn unsecure_join_goes_by_the_name_sent() {
+        let (mut app, server_addr) = unsecure_server(ServerSettings::default());
+        // Nothing stops an unsecure client filling in user data itself
+        let auth = ClientAuthentication::Unsecure {
+            protocol_id: PROTOCOL_ID,
+            client_id: 7,
+            server_addr,
+            user_data: Some(encode_user_data("Impostor").unwrap()),
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut transport = NetcodeClientTransport::new(now(), auth, socket).unwrap();
+        let mut client = RenetClient::new(connection_config());
+
+        let step = Duration::from_secs_f64(1.0 / f64::from(TICK_RATE));
+        let mut greeted = false;
+        for _ in 0..TICK_RATE {
+            client.update(step);
+            transport.update(step, &mut client).unwrap();
+            if client.is_connected() && !greeted {
+                greeted = true;
+                let hello = ClientMessage::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    build: BUILD.into(),
+                };
+                let join = ClientMessage::JoinRoom {
+                    name: "Plain".into(),
+                    room_code: None,
+                    spectate: false,
+                };
+                for msg in [hello, join] {
+                    client.send_message(msg.channel(), bincode::serialize(&msg).unwrap());
+                }
+            }
+            for channel in Channel::ALL {
+                while let Some(message) = client.receive_message(channel) {
+                    match bincode::deserialize(&message).unwrap() {
+                        ServerMessage::RoomState { players, .. } => {
+                            assert_eq!(players[0].name, "Plain");
+                            return;
+                        }
+                        ServerMessage::JoinRejected { reason } => panic!("{reason:?}"),
+                        _ => {}
+                    }
+                }
+            }
+            transport.send_packets(&mut client).unwrap();
+            app.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("never joined");
+    }
+}
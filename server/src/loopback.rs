@@ -0,0 +1,492 @@
+//! Bevy glue for the server's end of a `shared::LoopbackHub`, so a server
+//! and its clients can run in one process with no sockets: offline play,
+//! and end-to-end tests.
+
+use std::{thread, time::Duration};
+
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::prelude::*;
+use bevy_renet::renet::RenetServer;
+use bevy_renet::{RenetReceive, RenetSend, RenetServerPlugin};
+use shared::*;
+
+use crate::ServerPlugin;
+
+/// Serves clients connected through `hub` instead of over UDP.
+pub struct LoopbackServerPlugin {
+    pub hub: LoopbackHub,
+}
+
+/// The server's end of every loopback connection.
+#[derive(Resource, Deref, DerefMut)]
+pub struct LoopbackServer(pub LoopbackServerTransport);
+
+impl Plugin for LoopbackServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LoopbackServer(self.hub.server()))
+            .add_systems(
+                PreUpdate,
+                receive_server_packets
+                    .in_set(RenetReceive)
+                    .run_if(resource_exists::<RenetServer>)
+                    .after(RenetServerPlugin::update_system)
+                    .before(RenetServerPlugin::emit_server_events_system),
+            )
+            .add_systems(
+                PostUpdate,
+                send_server_packets
+                    .in_set(RenetSend)
+                    .run_if(resource_exists::<RenetServer>),
+            );
+    }
+}
+
+fn receive_server_packets(
+    time: Res<Time>,
+    mut transport: ResMut<LoopbackServer>,
+    mut server: ResMut<RenetServer>,
+) {
+    transport.update(time.delta(), &mut server);
+}
+
+fn send_server_packets(mut transport: ResMut<LoopbackServer>, mut server: ResMut<RenetServer>) {
+    transport.send_packets(&mut server);
+}
+
+/// Runs a server on its own thread, serving clients connected through
+/// `hub`. It lives as long as the process does.
+pub fn spawn_server(settings: ServerSettings, hub: LoopbackHub) {
+    thread::spawn(move || {
+        let tick = Duration::from_secs_f64(1.0 / f64::from(settings.tick_rate));
+        App::new()
+            .add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(tick)))
+            .add_plugins(ServerPlugin { settings })
+            .add_plugins(LoopbackServerPlugin { hub })
+            .run();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::time::TimeUpdateStrategy;
+    use bevy_renet::renet::RenetClient;
+
+    use super::*;
+    use crate::{Rooms, ServerTick};
+
+    /// A bare client driven by hand, one step per server update.
+    struct TestClient {
+        client: RenetClient,
+        transport: LoopbackClientTransport,
+        inputs: InputHistory,
+    }
+
+    impl TestClient {
+        /// Connects through `hub` and says hello.
+        fn new(hub: &LoopbackHub) -> Self {
+            Self::over(hub.connect())
+        }
+
+        /// Says hello over `transport`.
+        fn over(transport: LoopbackClientTransport) -> Self {
+            let mut client = Self {
+                client: RenetClient::new(connection_config()),
+                transport,
+                inputs: InputHistory::default(),
+            };
+            client.send(&ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                build: BUILD.into(),
+            });
+            client
+        }
+
+        fn id(&self) -> u64 {
+            self.transport.client_id()
+        }
+
+        fn send(&mut self, msg: &ClientMessage) {
+            let bytes = bincode::serialize(msg).unwrap();
+            self.client.send_message(msg.channel(), bytes);
+        }
+
+        fn receive(&mut self, dt: Duration) -> Vec<ServerMessage> {
+            self.client.update(dt);
+            self.transport.update(dt, &mut self.client);
+            let mut messages = Vec::new();
+            for channel in Channel::ALL {
+                while let Some(message) = self.client.receive_message(channel) {
+                    messages.push(bincode::deserialize(&message).unwrap());
+                }
+            }
+            messages
+        }
+    }
+
+    fn server_app(settings: ServerSettings, hub: &LoopbackHub) -> App {
+        let step = Duration::from_secs_f64(1.0 / f64::from(settings.tick_rate));
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(step))
+            .add_plugins(ServerPlugin { settings })
+            .add_plugins(LoopbackServerPlugin { hub: hub.clone() });
+        app
+    }
+
+    /// One server update, with each client sending before it and receiving
+    /// after it. Returns what each client received.
+    fn exchange(app: &mut App, clients: &mut [&mut TestClient]) -> Vec<Vec<ServerMessage>> {
+        let step = Duration::from_secs_f64(1.0 / f64::from(TICK_RATE));
+        for test_client in clients.iter_mut() {
+            test_client.transport.send_packets(&mut test_client.client);
+        }
+        app.update();
+        clients
+            .iter_mut()
+            .map(|test_client| test_client.receive(step))
+            .collect()
+    }
+
+    /// Enough exchanges for requests to be answered, with everything each
+    /// client received meanwhile.
+    fn settle(app: &mut App, clients: &mut [&mut TestClient]) -> Vec<Vec<ServerMessage>> {
+        let mut received = vec![Vec::new(); clients.len()];
+        for _ in 0..5 {
+            for (all, new) in received.iter_mut().zip(exchange(app, clients)) {
+                all.extend(new);
+            }
+        }
+        received
+    }
+
+    /// Runs a one-player race over a loopback connection with `conditions`
+    /// on the server's end, returning the leaderboard.
+    fn solo_race(conditions: NetworkConditions) -> Vec<LeaderboardEntry> {
+        let settings = ServerSettings {
+            countdown_ms: 100,
+            ..Default::default()
+        };
+        let hub = LoopbackHub::new();
+        let mut app = server_app(settings, &hub);
+        app.world_mut()
+            .resource_mut::<LoopbackServer>()
+            .set_conditions(conditions);
+
+        let mut player = TestClient::new(&hub);
+        player.send(&ClientMessage::JoinRoom {
+            name: "Solo".into(),
+            room_code: None,
+            spectate: false,
+        });
+        player.send(&ClientMessage::SetReady { ready: true });
+
+        let mut joined = false;
+        let mut raced = false;
+        let mut tick = 0;
+        // A full-speed swim takes well under a minute
+        for _ in 0..60 * TICK_RATE {
+            let [received] = exchange(&mut app, &mut [&mut player]).try_into().unwrap();
+            for msg in received {
+                match msg {
+                    ServerMessage::Joined { session_token, .. } => {
+                        joined = session_token.is_some();
+                    }
+                    ServerMessage::RoomState { state, .. } => {
+                        raced |= state == RoomPhase::Racing;
+                    }
+                    ServerMessage::RaceFinished { leaderboard } => {
+                        assert!(joined && raced);
+                        return leaderboard;
+                    }
+                    ServerMessage::Snapshot { last_input, .. }
+                    | ServerMessage::SnapshotDelta { last_input, .. } => {
+                        if let Some(last_input) = last_input {
+                            player.inputs.ack(last_input);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if joined {
+                tick += 1;
+                player.inputs.record(InputFrame {
+                    tick,
+                    up: true,
+                    ..Default::default()
+                });
+                let frames = player.inputs.latest(INPUT_REDUNDANCY);
+                player.send(&ClientMessage::Inputs(frames));
+            }
+        }
+        panic!("race never finished");
+    }
+
+    #[test]
+    fn solo_race_runs_to_the_finish() {
+        let leaderboard = solo_race(NetworkConditions::default());
+        assert_eq!(leaderboard.len(), 1);
+        assert_eq!(leaderboard[0].name, "Solo");
+    }
+
+    #[test]
+    fn race_survives_a_poor_connection() {
+        let leaderboard = solo_race(NetworkConditions::POOR);
+        assert_eq!(leaderboard.len(), 1);
+    }
+
+    #[test]
+    fn host_kicks_bans_and_locks() {
+        let hub = LoopbackHub::new();
+        let mut app = server_app(ServerSettings::default(), &hub);
+        let mut host = TestClient::new(&hub);
+        host.send(&ClientMessage::JoinRoom {
+            name: "Host".into(),
+            room_code: None,
+            spectate: false,
+        });
+        let room_code = settle(&mut app, &mut [&mut host])
+            .concat()
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Joined { room_code, .. } => Some(room_code),
+                _ => None,
+            })
+            .expect("host joined");
+
+        let mut guest = TestClient::new(&hub);
+        guest.send(&ClientMessage::JoinRoom {
+            name: "Guest".into(),
+            room_code: Some(room_code.clone()),
+            spectate: false,
+        });
+        settle(&mut app, &mut [&mut host, &mut guest]);
+        host.send(&ClientMessage::Kick {
+            id: guest.id(),
+            ban: true,
+        });
+        host.send(&ClientMessage::LockRoom { locked: true });
+        let [to_host, to_guest] = settle(&mut app, &mut [&mut host, &mut guest])
+            .try_into()
+            .unwrap();
+        assert!(to_guest.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Kicked {
+                reason: KickReason::Banned
+            }
+        )));
+        assert!(to_host.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::RoomState { players, locked: true, banned, .. }
+                if players.len() == 1 && banned == &["Guest"]
+        )));
+
+        let mut late = TestClient::new(&hub);
+        late.send(&ClientMessage::JoinRoom {
+            name: "Late".into(),
+            room_code: Some(room_code),
+            spectate: true,
+        });
+        let to_late = settle(&mut app, &mut [&mut late]).concat();
+        assert!(to_late.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::JoinRejected {
+                reason: JoinRejectReason::RoomLocked
+            }
+        )));
+    }
+
+    #[test]
+    fn outdated_clients_are_told_then_hung_up_on() {
+        let hub = LoopbackHub::new();
+        let mut app = server_app(ServerSettings::default(), &hub);
+        let mut outdated = TestClient {
+            client: RenetClient::new(connection_config()),
+            transport: hub.connect(),
+            inputs: InputHistory::default(),
+        };
+        outdated.send(&ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION - 1,
+            build: "0.0.0".into(),
+        });
+        let received = settle(&mut app, &mut [&mut outdated]).concat();
+        assert!(received.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::JoinRejected {
+                reason: JoinRejectReason::VersionMismatch { .. }
+            }
+        )));
+
+        for _ in 0..2 * TICK_RATE {
+            exchange(&mut app, &mut [&mut outdated]);
+        }
+        assert!(outdated.client.is_disconnected());
+    }
+
+    #[test]
+    fn stale_inputs_are_dropped_but_future_ones_strike() {
+        let hub = LoopbackHub::new();
+        let mut app = server_app(ServerSettings::default(), &hub);
+        // Up for longer than the input window
+        for _ in 0..4 * TICK_RATE {
+            app.update();
+        }
+        let server_tick = app.world().resource::<ServerTick>().0;
+        assert!(server_tick > INPUT_WINDOW_SECONDS * TICK_RATE);
+
+        let mut player = TestClient::new(&hub);
+        player.send(&ClientMessage::JoinRoom {
+            name: "Newcomer".into(),
+            room_code: None,
+            spectate: false,
+        });
+        // Counting from 1, as a client would before its clock synced
+        for tick in 1..=2 * TICK_RATE {
+            player.send(&ClientMessage::Inputs(vec![InputFrame {
+                tick,
+                ..Default::default()
+            }]));
+            exchange(&mut app, &mut [&mut player]);
+        }
+        assert!(player.client.is_connected());
+
+        let ahead = server_tick + 10 * INPUT_WINDOW_SECONDS * TICK_RATE;
+        for tick in ahead..ahead + MAX_STRIKES {
+            player.send(&ClientMessage::Inputs(vec![InputFrame {
+                tick,
+                ..Default::default()
+            }]));
+            exchange(&mut app, &mut [&mut player]);
+        }
+        settle(&mut app, &mut [&mut player]);
+        assert!(player.client.is_disconnected());
+    }
+
+    #[test]
+    fn resuming_leaves_the_room_joined_since() {
+        let hub = LoopbackHub::new();
+        let mut app = server_app(ServerSettings::default(), &hub);
+        let mut first = TestClient::new(&hub);
+        let mut second = TestClient::new(&hub);
+        for client in [&mut first, &mut second] {
+            client.send(&ClientMessage::JoinRoom {
+                name: "Swimmer".into(),
+                room_code: None,
+                spectate: false,
+            });
+        }
+        let [to_first, _] = settle(&mut app, &mut [&mut first, &mut second])
+            .try_into()
+            .unwrap();
+        let (room_code, session_token) = to_first
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Joined {
+                    room_code,
+                    session_token: Some(session_token),
+                    ..
+                } => Some((room_code, session_token)),
+                _ => None,
+            })
+            .expect("first joined");
+
+        second.send(&ClientMessage::Resume { session_token });
+        settle(&mut app, &mut [&mut second]);
+        let rooms = app.world().resource::<Rooms>();
+        assert_eq!(rooms.rooms.len(), 1);
+        let players: Vec<u64> = rooms.rooms[&room_code].players.keys().copied().collect();
+        assert_eq!(players, [second.id()]);
+    }
+
+    #[test]
+    fn resuming_under_the_same_id_gets_snapshots_again() {
+        let settings = ServerSettings {
+            countdown_ms: 100,
+            ..Default::default()
+        };
+        let hub = LoopbackHub::new();
+        let mut app = server_app(settings, &hub);
+        let mut player = TestClient::new(&hub);
+        player.send(&ClientMessage::JoinRoom {
+            name: "Swimmer".into(),
+            room_code: None,
+            spectate: false,
+        });
+        player.send(&ClientMessage::SetReady { ready: true });
+        let session_token = settle(&mut app, &mut [&mut player])
+            .concat()
+            .into_iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Joined { session_token, .. } => session_token,
+                _ => None,
+            })
+            .expect("player joined");
+        settle(&mut app, &mut [&mut player]);
+        let rooms = app.world().resource::<Rooms>();
+        assert!(rooms
+            .rooms
+            .values()
+            .all(|room| room.phase == RoomPhase::Racing));
+
+        let client_id = player.id();
+        player.transport.disconnect();
+        settle(&mut app, &mut []);
+        let rooms = app.world().resource::<Rooms>();
+        assert!(rooms.membership.is_empty());
+
+        let mut player = TestClient::over(hub.reconnect(client_id));
+        player.send(&ClientMessage::Resume { session_token });
+        let received = settle(&mut app, &mut [&mut player]).concat();
+        assert!(received
+            .iter()
+            .any(|msg| matches!(msg, ServerMessage::Joined { .. })));
+        assert!(received.iter().any(|msg| matches!(
+            msg,
+            ServerMessage::Snapshot { .. } | ServerMessage::SnapshotDelta { .. }
+        )));
+    }
+
+    #[test]
+    fn host_changes_room_settings_in_the_lobby() {
+        let hub = LoopbackHub::new();
+        let mut app = server_app(ServerSettings::default(), &hub);
+        let mut host = TestClient::new(&hub);
+        host.send(&ClientMessage::JoinRoom {
+            name: "Host".into(),
+            room_code: None,
+            spectate: false,
+        });
+        settle(&mut app, &mut [&mut host]);
+
+        let sprint = RoomSettings {
+            track: Track::Sprint,
+            mode: GameMode::AllFinish,
+            ..Default::default()
+        };
+        let empty = RoomSettings {
+            max_racers: 0,
+            ..Default::default()
+        };
+        // Would never leave the countdown
+        let no_countdown = RoomSettings {
+            countdown_ms: 0,
+            ..Default::default()
+        };
+        host.send(&ClientMessage::SetRoomSettings {
+            settings: Box::new(sprint.clone()),
+        });
+        for settings in [empty, no_countdown] {
+            host.send(&ClientMessage::SetRoomSettings {
+                settings: Box::new(settings),
+            });
+        }
+        let states: Vec<RoomSettings> = settle(&mut app, &mut [&mut host])
+            .concat()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::RoomState { settings, .. } => Some(*settings),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(states.last(), Some(&sprint));
+    }
+}
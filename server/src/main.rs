@@ -1,261 +1,145 @@
-use std::{collections::HashMap, net::UdpSocket, time::SystemTime};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::SystemTime,
+};
 
 use bevy::prelude::*;
 use bevy_renet::netcode::{
     NetcodeServerPlugin, NetcodeServerTransport, ServerAuthentication, ServerConfig,
 };
-use bevy_renet::renet::{ConnectionConfig, RenetServer, ServerEvent};
-use bevy_renet::RenetServerPlugin;
-use rand::Rng;
+use clap::Parser;
+use server::ServerPlugin;
 use shared::*;
 
-const PORT: u16 = 5000;
-
-#[derive(Resource)]
-struct RoomState {
-    code: String,
-    players: HashMap<u64, PlayerState>,
-    phase: RoomPhase,
-    countdown: u32,
-    tick: u32,
+/// Address clients are told to reach us on; connect tokens must list it.
+const PUBLIC_ADDR_ENV: &str = "ODYSSEY_PUBLIC_ADDR";
+
+/// Odyssey race server. Flags take precedence over the config file.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// TOML file with any of the settings below, in snake_case
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0:5000]
+    #[arg(long)]
+    bind_addr: Option<SocketAddr>,
+    /// Address clients reach us on, if not the bind address
+    #[arg(long, env = PUBLIC_ADDR_ENV)]
+    public_addr: Option<SocketAddr>,
+    /// Racers per room
+    #[arg(long)]
+    max_players: Option<usize>,
+    /// Rooms hosted at once
+    #[arg(long)]
+    max_rooms: Option<usize>,
+    /// Simulation steps per second
+    #[arg(long)]
+    tick_rate: Option<u32>,
+    /// Snapshots per second; must divide the tick rate
+    #[arg(long)]
+    snapshot_rate: Option<u32>,
+    /// Length of the pre-race countdown
+    #[arg(long)]
+    countdown_ms: Option<u32>,
+    /// Accept connect tokens only; the key comes from the config file or
+    /// ODYSSEY_PRIVATE_KEY
+    #[arg(long)]
+    secure: bool,
 }
 
-#[derive(Debug)]
-struct PlayerState {
-    name: String,
-    ready: bool,
-    is_host: bool,
-    kin: PlayerKinematics,
-    last_input: InputFrame,
-    finished_tick: Option<u32>,
+impl Args {
+    /// The config file's settings with flags applied on top, validated.
+    fn settings(self) -> Result<ServerSettings, ConfigError> {
+        let mut settings: ServerSettings = load_config(self.config.as_deref())?;
+        settings.bind_addr = self.bind_addr.unwrap_or(settings.bind_addr);
+        settings.public_addr = self.public_addr.or(settings.public_addr);
+        settings.max_players = self.max_players.unwrap_or(settings.max_players);
+        settings.max_rooms = self.max_rooms.unwrap_or(settings.max_rooms);
+        settings.tick_rate = self.tick_rate.unwrap_or(settings.tick_rate);
+        settings.snapshot_rate = self.snapshot_rate.unwrap_or(settings.snapshot_rate);
+        settings.countdown_ms = self.countdown_ms.unwrap_or(settings.countdown_ms);
+        settings.auth.secure |= self.secure;
+        // Kept out of the flags so the key doesn't show up in process lists
+        if let Ok(hex) = std::env::var(PRIVATE_KEY_ENV) {
+            settings.auth.private_key = Some(hex);
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
 }
 
 fn main() {
+    let settings = Args::parse().settings().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let (socket, _relay) = bind(&settings);
     let mut app = App::new();
     app.add_plugins(MinimalPlugins)
-        .add_plugins(RenetServerPlugin)
         .add_plugins(NetcodeServerPlugin)
-        .insert_resource(new_server())
-        .insert_resource(new_transport())
-        .insert_resource(RoomState {
-            code: random_room_code(),
-            players: HashMap::new(),
-            phase: RoomPhase::Lobby,
-            countdown: 3_000,
-            tick: 0,
-        })
-        .add_systems(
-            Update,
-            (handle_events, network_receive_system, broadcast_room_state),
-        )
-        .add_systems(
-            FixedUpdate,
-            (
-                apply_inputs,
-                physics_step,
-                race_state_system,
-                snapshot_broadcast_system,
-            )
-                .chain(),
-        )
-        .insert_resource(Time::<Fixed>::from_hz(TICK_RATE as f64))
+        .insert_resource(new_transport(&settings, socket))
+        .add_plugins(ServerPlugin { settings })
         .run();
 }
 
-fn new_transport() -> NetcodeServerTransport {
-    let public_addr = format!("0.0.0.0:{}", PORT).parse().unwrap();
-    let socket = UdpSocket::bind(public_addr).unwrap();
+/// The socket netcode serves on. When `network` impairs traffic, clients
+/// reach it through a relay on `bind_addr` instead, running for as long as
+/// the returned handle is kept. Every client then seems to come from the
+/// relay's address, so unsecure bans catch them all.
+fn bind(settings: &ServerSettings) -> (UdpSocket, Option<UdpRelay>) {
+    let addr = settings.bind_addr;
+    if settings.network.is_ideal() {
+        let socket = UdpSocket::bind(addr).unwrap_or_else(|err| bind_failed(addr, err));
+        return (socket, None);
+    }
+    let inner = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let socket = UdpSocket::bind(inner).unwrap_or_else(|err| bind_failed(inner, err));
+    let relay = socket
+        .local_addr()
+        .and_then(|inner| UdpRelay::spawn(addr, inner, settings.network))
+        .unwrap_or_else(|err| bind_failed(addr, err));
+    println!("Simulated network: {}", settings.network);
+    (socket, Some(relay))
+}
+
+fn bind_failed(addr: SocketAddr, err: io::Error) -> ! {
+    eprintln!("Could not bind {addr}: {err}");
+    std::process::exit(1);
+}
+
+fn new_transport(settings: &ServerSettings, socket: UdpSocket) -> NetcodeServerTransport {
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
     let server_config = ServerConfig {
         current_time,
-        max_clients: MAX_PLAYERS,
+        max_clients: settings.max_clients(),
         protocol_id: PROTOCOL_ID,
-        public_addresses: vec![public_addr],
-        authentication: ServerAuthentication::Unsecure,
+        public_addresses: vec![settings.public_addr()],
+        authentication: server_authentication(&settings.auth),
     };
-    NetcodeServerTransport::new(server_config, socket).unwrap()
-}
-
-fn new_server() -> RenetServer {
-    let server_config = ConnectionConfig::default();
-    RenetServer::new(server_config)
+    NetcodeServerTransport::new(server_config, socket).unwrap_or_else(|err| {
+        eprintln!("Could not start the netcode transport: {err}");
+        std::process::exit(1);
+    })
 }
 
-fn handle_events(mut events: MessageReader<ServerEvent>, mut room: ResMut<RoomState>) {
-    for event in events.read() {
-        match event {
-            ServerEvent::ClientConnected { client_id, .. } => {
-                let is_host = room.players.is_empty();
-                room.players.insert(
-                    *client_id,
-                    PlayerState {
-                        name: format!("Guest-{}", client_id),
-                        ready: false,
-                        is_host,
-                        kin: PlayerKinematics::spawn(start_position()),
-                        last_input: InputFrame::default(),
-                        finished_tick: None,
-                    },
-                );
-                info!("Client {client_id} connected");
-            }
-            ServerEvent::ClientDisconnected { client_id, .. } => {
-                room.players.remove(client_id);
-                info!("Client {client_id} disconnected");
-                if room.players.is_empty() {
-                    room.phase = RoomPhase::Lobby;
-                }
-            }
+/// Secure when `auth.secure` is set, otherwise anyone knowing
+/// `PROTOCOL_ID` may connect. The settings have been validated, so a
+/// secure server has a usable key.
+fn server_authentication(auth: &AuthSettings) -> ServerAuthentication {
+    match auth.private_key() {
+        Ok(Some(private_key)) => {
+            println!("Accepting connect tokens only");
+            ServerAuthentication::Secure { private_key }
         }
-    }
-}
-
-fn network_receive_system(mut server: ResMut<RenetServer>, mut room: ResMut<RoomState>) {
-    for client_id in server.clients_id() {
-        while let Some(message) = server.receive_message(client_id, 0) {
-            if let Ok(msg) = bincode::deserialize::<ClientMessage>(&message) {
-                match msg {
-                    ClientMessage::JoinRoom { name, .. } => {
-                        if let Some(player) = room.players.get_mut(&client_id) {
-                            player.name = name;
-                        }
-                    }
-                    ClientMessage::SetReady { ready } => {
-                        if let Some(player) = room.players.get_mut(&client_id) {
-                            player.ready = ready;
-                        }
-                        if room.players.values().all(|p| p.ready)
-                            && matches!(room.phase, RoomPhase::Lobby)
-                        {
-                            room.phase = RoomPhase::Countdown;
-                            room.countdown = 3_000;
-                        }
-                    }
-                    ClientMessage::InputFrame(input) => {
-                        if let Some(player) = room.players.get_mut(&client_id) {
-                            player.last_input = input;
-                        }
-                    }
-                    ClientMessage::StartRace => {
-                        if let Some(player) = room.players.get(&client_id) {
-                            if player.is_host && matches!(room.phase, RoomPhase::Lobby) {
-                                room.phase = RoomPhase::Countdown;
-                                room.countdown = 3_000;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
-fn apply_inputs(mut room: ResMut<RoomState>) {
-    if !matches!(room.phase, RoomPhase::Countdown | RoomPhase::Racing) {
-        return;
-    }
-    let dt = 1.0 / TICK_RATE as f32;
-    for player in room.players.values_mut() {
-        let mut kin = integrate_input(player.kin.clone(), &player.last_input, dt);
-        let region = region_for_position(kin.position);
-        let radius = tube_radius(region);
-        kin.position = clamp_to_radius(kin.position, radius);
-        player.kin = kin;
-    }
-}
-
-fn physics_step(mut room: ResMut<RoomState>) {
-    if matches!(room.phase, RoomPhase::Countdown) {
-        if room.countdown > 0 {
-            room.countdown = room.countdown.saturating_sub(1000 / TICK_RATE);
-            if room.countdown == 0 {
-                room.phase = RoomPhase::Racing;
-            }
+        _ => {
+            println!("Accepting unsecure connections");
+            ServerAuthentication::Unsecure
         }
-        return;
     }
-
-    if !matches!(room.phase, RoomPhase::Racing) {
-        return;
-    }
-
-    room.tick = room.tick.wrapping_add(1);
-    let current_tick = room.tick;
-    for player in room.players.values_mut() {
-        if region_for_position(player.kin.position) == RegionId::Ampulla
-            && player.finished_tick.is_none()
-        {
-            player.finished_tick = Some(current_tick);
-        }
-    }
-}
-
-fn race_state_system(mut room: ResMut<RoomState>) {
-    if matches!(room.phase, RoomPhase::Racing)
-        && room.players.values().any(|p| p.finished_tick.is_some())
-    {
-        room.phase = RoomPhase::Finished;
-    }
-}
-
-fn snapshot_broadcast_system(mut server: ResMut<RenetServer>, room: Res<RoomState>) {
-    if !matches!(room.phase, RoomPhase::Racing | RoomPhase::Countdown) {
-        return;
-    }
-
-    let entities = room
-        .players
-        .iter()
-        .map(|(id, player)| EntitySnapshot {
-            id: *id,
-            position: player.kin.position.to_array(),
-            velocity: player.kin.velocity.to_array(),
-            stamina: player.kin.stamina,
-            region: region_for_position(player.kin.position),
-        })
-        .collect();
-
-    let snapshot = ServerMessage::Snapshot {
-        tick: room.tick,
-        entities,
-    };
-    let payload = bincode::serialize(&snapshot).unwrap();
-    for client_id in server.clients_id() {
-        server.send_message(client_id, 0, payload.clone());
-    }
-}
-
-fn broadcast_room_state(mut server: ResMut<RenetServer>, room: Res<RoomState>) {
-    let msg = ServerMessage::RoomState {
-        room_code: room.code.clone(),
-        players: room
-            .players
-            .iter()
-            .map(|(id, p)| PlayerSummary {
-                id: *id,
-                name: p.name.clone(),
-                ready: p.ready,
-                is_host: p.is_host,
-            })
-            .collect(),
-        state: room.phase.clone(),
-    };
-
-    let payload = bincode::serialize(&msg).unwrap();
-    for client_id in server.clients_id() {
-        server.send_message(client_id, 0, payload.clone());
-    }
-}
-
-fn random_room_code() -> String {
-    const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ";
-    let mut rng = rand::thread_rng();
-    (0..4)
-        .map(|_| {
-            let idx = rng.gen_range(0..ALPHABET.len());
-            ALPHABET[idx] as char
-        })
-        .collect()
 }
@@ -0,0 +1,104 @@
+use thiserror::Error;
+
+/// Length of the netcode private key shared by the server and token issuer.
+pub const PRIVATE_KEY_BYTES: usize = 32;
+/// Length of the user data blob carried inside a netcode connect token.
+pub const USER_DATA_BYTES: usize = 256;
+/// How long an issued connect token can be used to connect, in seconds.
+pub const TOKEN_EXPIRE_SECONDS: u64 = 3600;
+/// Seconds without packets before netcode drops a token-authenticated client.
+pub const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+
+/// Hex-encoded private key; when set the server only accepts connect tokens.
+pub const PRIVATE_KEY_ENV: &str = "ODYSSEY_PRIVATE_KEY";
+/// Path to a connect token file; when set the client connects securely.
+pub const CONNECT_TOKEN_ENV: &str = "ODYSSEY_CONNECT_TOKEN";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("private key must be {expected} hex characters, got {got}")]
+    KeyLength { expected: usize, got: usize },
+    #[error("private key contains non-hex characters")]
+    KeyNotHex,
+    #[error("player name must be 1 to {USER_DATA_BYTES} bytes")]
+    NameLength,
+}
+
+pub fn parse_private_key(hex: &str) -> Result<[u8; PRIVATE_KEY_BYTES], AuthError> {
+    let hex = hex.trim();
+    if hex.len() != PRIVATE_KEY_BYTES * 2 {
+        return Err(AuthError::KeyLength {
+            expected: PRIVATE_KEY_BYTES * 2,
+            got: hex.len(),
+        });
+    }
+
+    let mut key = [0u8; PRIVATE_KEY_BYTES];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| AuthError::KeyNotHex)?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| AuthError::KeyNotHex)?;
+    }
+    Ok(key)
+}
+
+pub fn private_key_to_hex(key: &[u8; PRIVATE_KEY_BYTES]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Packs a player name into connect token user data, zero padded.
+pub fn encode_user_data(name: &str) -> Result<[u8; USER_DATA_BYTES], AuthError> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes.len() > USER_DATA_BYTES {
+        return Err(AuthError::NameLength);
+    }
+    let mut data = [0u8; USER_DATA_BYTES];
+    data[..bytes.len()].copy_from_slice(bytes);
+    Ok(data)
+}
+
+/// Recovers the player name stored by [`encode_user_data`].
+pub fn decode_user_data(data: &[u8; USER_DATA_BYTES]) -> Option<String> {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(USER_DATA_BYTES);
+    if end == 0 {
+        return None;
+    }
+    String::from_utf8(data[..end].to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_key_round_trip() {
+        let mut key = [0u8; PRIVATE_KEY_BYTES];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        let hex = private_key_to_hex(&key);
+        assert_eq!(parse_private_key(&hex), Ok(key));
+    }
+
+    #[test]
+    fn rejects_bad_private_keys() {
+        assert_eq!(
+            parse_private_key("abcd"),
+            Err(AuthError::KeyLength {
+                expected: 64,
+                got: 4
+            })
+        );
+        assert_eq!(
+            parse_private_key(&"zz".repeat(32)),
+            Err(AuthError::KeyNotHex)
+        );
+    }
+
+    #[test]
+    fn user_data_round_trip() {
+        let data = encode_user_data("Explorer-42").unwrap();
+        assert_eq!(decode_user_data(&data).as_deref(), Some("Explorer-42"));
+        assert_eq!(encode_user_data(""), Err(AuthError::NameLength));
+        assert_eq!(decode_user_data(&[0u8; USER_DATA_BYTES]), None);
+    }
+}
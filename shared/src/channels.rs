@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use renet::{ChannelConfig, ConnectionConfig, SendType};
+
+use crate::{ClientMessage, ServerMessage};
+
+const CHANNEL_MEMORY_BYTES: usize = 5 * 1024 * 1024;
+const RESEND_TIME: Duration = Duration::from_millis(200);
+
+/// Renet channels used by both directions of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Lobby, chat and results: must arrive, in order.
+    Reliable,
+    /// Snapshots and inputs: only the newest matters, so never wait on a lost one.
+    Unreliable,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 2] = [Channel::Reliable, Channel::Unreliable];
+
+    pub fn id(self) -> u8 {
+        match self {
+            Channel::Reliable => 0,
+            Channel::Unreliable => 1,
+        }
+    }
+
+    fn config(self) -> ChannelConfig {
+        let send_type = match self {
+            Channel::Reliable => SendType::ReliableOrdered {
+                resend_time: RESEND_TIME,
+            },
+            Channel::Unreliable => SendType::Unreliable,
+        };
+        ChannelConfig {
+            channel_id: self.id(),
+            max_memory_usage_bytes: CHANNEL_MEMORY_BYTES,
+            send_type,
+        }
+    }
+}
+
+impl From<Channel> for u8 {
+    fn from(channel: Channel) -> Self {
+        channel.id()
+    }
+}
+
+/// Connection config shared by server and client so channel ids always agree.
+pub fn connection_config() -> ConnectionConfig {
+    let channels: Vec<ChannelConfig> = Channel::ALL.iter().map(|c| c.config()).collect();
+    ConnectionConfig {
+        available_bytes_per_tick: 60_000,
+        server_channels_config: channels.clone(),
+        client_channels_config: channels,
+    }
+}
+
+impl ClientMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            ClientMessage::Inputs(_)
+            | ClientMessage::SnapshotAck { .. }
+            | ClientMessage::Ping { .. } => Channel::Unreliable,
+            ClientMessage::Hello { .. }
+            | ClientMessage::JoinRoom { .. }
+            | ClientMessage::SetReady { .. }
+            | ClientMessage::StartRace
+            | ClientMessage::Resume { .. }
+            | ClientMessage::TransferHost { .. }
+            | ClientMessage::Kick { .. }
+            | ClientMessage::LockRoom { .. }
+            | ClientMessage::SetRoomSettings { .. } => Channel::Reliable,
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn channel(&self) -> Channel {
+        match self {
+            // Resent pongs would only inflate the measured round trip
+            ServerMessage::Snapshot { .. }
+            | ServerMessage::SnapshotDelta { .. }
+            | ServerMessage::Pong { .. } => Channel::Unreliable,
+            ServerMessage::Hello { .. }
+            | ServerMessage::JoinRejected { .. }
+            | ServerMessage::RoomState { .. }
+            | ServerMessage::Countdown { .. }
+            | ServerMessage::RaceFinished { .. }
+            | ServerMessage::Joined { .. }
+            | ServerMessage::Kicked { .. } => Channel::Reliable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_ids_are_unique_and_configured() {
+        let config = connection_config();
+        for (i, channel) in Channel::ALL.iter().enumerate() {
+            assert_eq!(config.server_channels_config[i].channel_id, channel.id());
+            assert_eq!(config.client_channels_config[i].channel_id, channel.id());
+        }
+        assert_ne!(Channel::Reliable.id(), Channel::Unreliable.id());
+    }
+
+    #[test]
+    fn results_are_reliable_and_snapshots_are_not() {
+        let finished = ServerMessage::RaceFinished {
+            leaderboard: Vec::new(),
+        };
+        let snapshot = ServerMessage::Snapshot {
+            tick: 1,
+            last_input: None,
+            race_tick: 0,
+            entities: Vec::new(),
+        };
+        assert_eq!(finished.channel(), Channel::Reliable);
+        assert_eq!(snapshot.channel(), Channel::Unreliable);
+        assert_eq!(
+            ClientMessage::Inputs(Vec::new()).channel(),
+            Channel::Unreliable
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::TICK_RATE;
+
+/// Seconds between clock synchronization pings.
+pub const PING_INTERVAL_SECONDS: f64 = 0.5;
+/// Ticks inputs are stamped beyond their expected arrival, so they are
+/// already buffered when the server reaches them.
+pub const INPUT_LEAD_TICKS: f64 = 2.0;
+
+/// Client-side estimate of the round-trip time and of the server's tick
+/// timeline, fed by Ping/Pong exchanges.
+#[derive(Debug, Clone)]
+pub struct ClockSync {
+    tick_rate: u32,
+    /// Smoothed round-trip time in seconds.
+    rtt: Option<f64>,
+    /// Server tick minus local time, in ticks.
+    offset: Option<f64>,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new(TICK_RATE)
+    }
+}
+
+impl ClockSync {
+    /// Weight of a new round-trip sample, as in TCP's smoothed RTT.
+    const RTT_SMOOTHING: f64 = 0.125;
+    /// Weight of a new offset sample.
+    const OFFSET_SMOOTHING: f64 = 0.1;
+    /// Beyond this many ticks off the estimate the offset jumps instead.
+    const SNAP_TICKS: f64 = 30.0;
+    /// Input ticks this far from the target are realigned.
+    const INPUT_TOLERANCE_TICKS: f64 = 3.0;
+
+    /// A clock for a server running `tick_rate` ticks per second.
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            tick_rate,
+            rtt: None,
+            offset: None,
+        }
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// Records a Pong. `sent` and `received` are local times in seconds and
+    /// `server_tick` is the fractional server tick at which it was sent.
+    pub fn observe(&mut self, sent: f64, received: f64, server_tick: f64) {
+        let rtt = (received - sent).max(0.0);
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => smoothed + (rtt - smoothed) * Self::RTT_SMOOTHING,
+            None => rtt,
+        });
+
+        // The reply spent about half of its own round trip in flight
+        let ticks_per_second = f64::from(self.tick_rate);
+        let offset = server_tick + rtt / 2.0 * ticks_per_second - received * ticks_per_second;
+        self.offset = Some(match self.offset {
+            Some(current) if (offset - current).abs() < Self::SNAP_TICKS => {
+                current + (offset - current) * Self::OFFSET_SMOOTHING
+            }
+            _ => offset,
+        });
+    }
+
+    /// Smoothed round-trip time in seconds.
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    /// Estimated fractional server tick at local time `now`.
+    pub fn server_tick(&self, now: f64) -> Option<f64> {
+        Some(now * f64::from(self.tick_rate) + self.offset?)
+    }
+
+    /// Server tick an input sampled at `now` should be stamped with, so it
+    /// arrives just ahead of the server simulating it.
+    pub fn input_tick(&self, now: f64) -> Option<f64> {
+        let one_way = self.rtt? / 2.0 * f64::from(self.tick_rate);
+        Some(self.server_tick(now)? + one_way + INPUT_LEAD_TICKS)
+    }
+
+    /// Tick for the input following `previous`. Skips ahead when far behind
+    /// the server's timeline, and returns `None` when far ahead so the lead
+    /// shrinks without ticks ever going backwards. Also `None` before the
+    /// first sync, when there is no timeline to stamp inputs against.
+    pub fn next_input_tick(&self, now: f64, previous: u32) -> Option<u32> {
+        let next = previous.wrapping_add(1);
+        let target = self.input_tick(now)?;
+        let drift = target - f64::from(next);
+        if drift > Self::INPUT_TOLERANCE_TICKS {
+            Some(target.round() as u32)
+        } else if drift < -Self::INPUT_TOLERANCE_TICKS {
+            None
+        } else {
+            Some(next)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs pings every half second against a server whose tick is
+    /// `offset_ticks` ahead of local time, with a fixed one-way delay.
+    fn synced(offset_ticks: f64, one_way: f64) -> ClockSync {
+        let mut clock = ClockSync::default();
+        for i in 0..40 {
+            let sent = f64::from(i) * PING_INTERVAL_SECONDS;
+            let server_tick = (sent + one_way) * f64::from(TICK_RATE) + offset_ticks;
+            clock.observe(sent, sent + 2.0 * one_way, server_tick);
+        }
+        clock
+    }
+
+    #[test]
+    fn estimates_rtt_and_server_tick() {
+        let clock = synced(5_000.0, 0.05);
+        assert!((clock.rtt().unwrap() - 0.1).abs() < 1e-9);
+
+        let now = 30.0;
+        let expected = now * f64::from(TICK_RATE) + 5_000.0;
+        assert!((clock.server_tick(now).unwrap() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn jitter_is_smoothed() {
+        let mut clock = synced(0.0, 0.05);
+        let before = clock.server_tick(20.0).unwrap();
+
+        // One badly delayed pong moves the estimate only a little
+        let sent = 20.0;
+        clock.observe(sent, sent + 0.5, (sent + 0.05) * f64::from(TICK_RATE));
+        let rtt = clock.rtt().unwrap();
+        assert!(rtt > 0.1 && rtt < 0.2);
+        assert!((clock.server_tick(20.0).unwrap() - before).abs() < 2.0);
+    }
+
+    #[test]
+    fn input_ticks_follow_the_server_timeline() {
+        let clock = synced(1_000.0, 0.05);
+        let now = 10.0;
+        let target = clock.input_tick(now).unwrap();
+        // Local time plus offset, one-way delay and lead
+        assert!((target - (600.0 + 1_000.0 + 3.0 + INPUT_LEAD_TICKS)).abs() < 1e-6);
+
+        // Far behind: jump onto the timeline
+        assert_eq!(clock.next_input_tick(now, 0), Some(target.round() as u32));
+        // Close enough: keep counting
+        assert_eq!(clock.next_input_tick(now, 1_604), Some(1_605));
+        // Far ahead: hold back a tick
+        assert_eq!(clock.next_input_tick(now, 1_700), None);
+
+        // Without a sync there is nothing to send yet
+        assert_eq!(ClockSync::default().next_input_tick(now, 7), None);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{fmt, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::ConfigError;
+
+/// Extra delay of a packet picked for reordering, so that packets sent
+/// after it overtake it.
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+/// Impairments applied to packets in one direction. The default is a
+/// perfect network.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConditions {
+    /// One-way delay added to every packet.
+    pub latency_ms: u32,
+    /// Each packet's delay varies by up to this much either way.
+    pub jitter_ms: u32,
+    /// Chance of a packet being dropped, 0 to 1.
+    pub loss: f64,
+    /// Chance of a packet arriving twice, 0 to 1.
+    pub duplicate: f64,
+    /// Chance of a packet being held back behind later ones, 0 to 1.
+    pub reorder: f64,
+}
+
+impl NetworkConditions {
+    /// A mobile connection on a bad day.
+    pub const POOR: Self = Self {
+        latency_ms: 120,
+        jitter_ms: 40,
+        loss: 0.05,
+        duplicate: 0.01,
+        reorder: 0.02,
+    };
+
+    pub fn is_ideal(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (field, chance) in [
+            ("network.loss", self.loss),
+            ("network.duplicate", self.duplicate),
+            ("network.reorder", self.reorder),
+        ] {
+            if !(0.0..=1.0).contains(&chance) {
+                return Err(ConfigError::Invalid {
+                    field,
+                    expected: "between 0 and 1",
+                    got: chance.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NetworkConditions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ideal() {
+            return write!(f, "ideal");
+        }
+        write!(
+            f,
+            "{}±{} ms, {:.0}% loss, {:.0}% duplicated, {:.0}% reordered",
+            self.latency_ms,
+            self.jitter_ms,
+            self.loss * 100.0,
+            self.duplicate * 100.0,
+            self.reorder * 100.0
+        )
+    }
+}
+
+/// Delays, drops, duplicates and reorders packets going one way, according
+/// to `NetworkConditions`. Seeded, so a run can be replayed exactly.
+#[derive(Debug)]
+pub struct PacketConditioner {
+    conditions: NetworkConditions,
+    rng: StdRng,
+    now: Duration,
+    /// Packets not yet delivered, with the time they are due at.
+    in_flight: Vec<(Duration, u64, Vec<u8>)>,
+    sent: u64,
+}
+
+impl PacketConditioner {
+    pub fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            in_flight: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        self.conditions
+    }
+
+    /// Applies to packets sent from now on; those in flight keep their delay.
+    pub fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.conditions = conditions;
+    }
+
+    pub fn advance(&mut self, dt: Duration) {
+        self.now += dt;
+    }
+
+    pub fn send(&mut self, packet: Vec<u8>) {
+        if self.rng.gen_bool(self.conditions.loss) {
+            return;
+        }
+        if self.rng.gen_bool(self.conditions.duplicate) {
+            self.enqueue(packet.clone());
+        }
+        self.enqueue(packet);
+    }
+
+    fn enqueue(&mut self, packet: Vec<u8>) {
+        let jitter = i64::from(self.conditions.jitter_ms);
+        let delay_ms = i64::from(self.conditions.latency_ms) + self.rng.gen_range(-jitter..=jitter);
+        let mut due = self.now + Duration::from_millis(delay_ms.max(0) as u64);
+        if self.rng.gen_bool(self.conditions.reorder) {
+            due += REORDER_HOLD;
+        }
+        self.in_flight.push((due, self.sent, packet));
+        self.sent += 1;
+    }
+
+    /// Packets due by now, earliest first.
+    pub fn receive(&mut self) -> Vec<Vec<u8>> {
+        let (mut due, in_flight): (Vec<_>, Vec<_>) = self
+            .in_flight
+            .drain(..)
+            .partition(|(at, _, _)| *at <= self.now);
+        self.in_flight = in_flight;
+        due.sort_by_key(|(at, sent, _)| (*at, *sent));
+        due.into_iter().map(|(_, _, packet)| packet).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(count: u8) -> impl Iterator<Item = Vec<u8>> {
+        (0..count).map(|i| vec![i])
+    }
+
+    #[test]
+    fn ideal_conditions_pass_packets_straight_through() {
+        let mut conditioner = PacketConditioner::new(NetworkConditions::default(), 1);
+        packets(10).for_each(|p| conditioner.send(p));
+        assert_eq!(conditioner.receive(), packets(10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn latency_holds_packets_back() {
+        let conditions = NetworkConditions {
+            latency_ms: 100,
+            ..Default::default()
+        };
+        let mut conditioner = PacketConditioner::new(conditions, 1);
+        conditioner.send(vec![1]);
+        conditioner.advance(Duration::from_millis(99));
+        assert!(conditioner.receive().is_empty());
+        conditioner.advance(Duration::from_millis(1));
+        assert_eq!(conditioner.receive(), vec![vec![1]]);
+    }
+
+    #[test]
+    fn loss_and_duplication_change_the_packet_count() {
+        let lossy = NetworkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        let mut conditioner = PacketConditioner::new(lossy, 1);
+        packets(10).for_each(|p| conditioner.send(p));
+        assert!(conditioner.receive().is_empty());
+
+        let doubled = NetworkConditions {
+            duplicate: 1.0,
+            ..Default::default()
+        };
+        let mut conditioner = PacketConditioner::new(doubled, 1);
+        packets(10).for_each(|p| conditioner.send(p));
+        assert_eq!(conditioner.receive().len(), 20);
+    }
+
+    #[test]
+    fn same_seed_replays_the_same_run() {
+        let run = |seed| {
+            let mut conditioner = PacketConditioner::new(NetworkConditions::POOR, seed);
+            let mut received = Vec::new();
+            for p in packets(100) {
+                conditioner.send(p);
+                conditioner.advance(Duration::from_millis(16));
+                received.extend(conditioner.receive());
+            }
+            conditioner.advance(Duration::from_secs(1));
+            received.extend(conditioner.receive());
+            received
+        };
+        let first = run(7);
+        assert_eq!(first, run(7));
+        // Jitter and reordering shuffle some packets
+        assert_ne!(first, {
+            let mut sorted = first.clone();
+            sorted.sort();
+            sorted
+        });
+    }
+
+    #[test]
+    fn chances_must_be_probabilities() {
+        let conditions = NetworkConditions {
+            loss: 1.5,
+            ..Default::default()
+        };
+        assert!(conditions.validate().is_err());
+        NetworkConditions::POOR.validate().unwrap();
+    }
+}
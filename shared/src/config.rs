@@ -0,0 +1,400 @@
+use std::{
+    fmt::Display,
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    parse_private_key, validate_name, AuthError, Environment, InputBufferConfig, NetworkConditions,
+    RoomSettings, COUNTDOWN_MS, INPUT_WINDOW_SECONDS, MAX_PLAYERS, MAX_SPECTATORS,
+    PRIVATE_KEY_BYTES, SNAPSHOT_RATE, TICK_RATE,
+};
+
+pub const DEFAULT_PORT: u16 = 5000;
+/// renetcode's `NETCODE_MAX_CLIENTS`; its server panics when asked for more.
+pub const MAX_CLIENTS: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{field} must be {expected}, got {got}")]
+    Invalid {
+        field: &'static str,
+        expected: &'static str,
+        got: String,
+    },
+    #[error("environment.{region}.{source}")]
+    InRegion {
+        region: &'static str,
+        source: Box<ConfigError>,
+    },
+}
+
+/// Reads a TOML config file, or returns the defaults when there is none.
+/// Keys left out of the file keep their default value.
+pub fn load_config<T: DeserializeOwned + Default>(path: Option<&Path>) -> Result<T, ConfigError> {
+    let Some(path) = path else {
+        return Ok(T::default());
+    };
+    let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.into(),
+        source,
+    })?;
+    toml::from_str(&text).map_err(|source| ConfigError::Parse {
+        path: path.into(),
+        source,
+    })
+}
+
+pub(crate) fn check(
+    ok: bool,
+    field: &'static str,
+    expected: &'static str,
+    got: impl Display,
+) -> Result<(), ConfigError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            field,
+            expected,
+            got: got.to_string(),
+        })
+    }
+}
+
+/// How the server lets clients in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Accept connect tokens only; anyone knowing `PROTOCOL_ID` may
+    /// connect otherwise.
+    pub secure: bool,
+    /// Hex key shared with the token issuer; `ODYSSEY_PRIVATE_KEY`
+    /// overrides it.
+    pub private_key: Option<String>,
+}
+
+impl AuthSettings {
+    /// The key connect tokens are checked against, when running secure.
+    pub fn private_key(&self) -> Result<Option<[u8; PRIVATE_KEY_BYTES]>, AuthError> {
+        if !self.secure {
+            return Ok(None);
+        }
+        parse_private_key(self.private_key.as_deref().unwrap_or_default()).map(Some)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind_addr: SocketAddr,
+    /// Address clients are told to reach us on; connect tokens must list
+    /// it. Defaults to `bind_addr`.
+    pub public_addr: Option<SocketAddr>,
+    pub max_players: usize,
+    pub max_rooms: usize,
+    pub tick_rate: u32,
+    pub snapshot_rate: u32,
+    pub countdown_ms: u32,
+    pub input_buffer: InputBufferConfig,
+    pub auth: AuthSettings,
+    /// Impairments applied both ways to every client's UDP traffic, by
+    /// relaying it; for trying the game under latency and loss.
+    pub network: NetworkConditions,
+    /// Region effects new rooms race with.
+    pub environment: Environment,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_addr: (Ipv4Addr::UNSPECIFIED, DEFAULT_PORT).into(),
+            public_addr: None,
+            max_players: MAX_PLAYERS,
+            max_rooms: 16,
+            tick_rate: TICK_RATE,
+            snapshot_rate: SNAPSHOT_RATE,
+            countdown_ms: COUNTDOWN_MS,
+            input_buffer: InputBufferConfig::default(),
+            auth: AuthSettings::default(),
+            network: NetworkConditions::default(),
+            environment: Environment::default(),
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn public_addr(&self) -> SocketAddr {
+        self.public_addr.unwrap_or(self.bind_addr)
+    }
+
+    /// Ticks between two snapshots.
+    pub fn snapshot_interval(&self) -> u32 {
+        self.tick_rate / self.snapshot_rate
+    }
+
+    /// Ticks an input may be stamped away from the current server tick.
+    pub fn input_window(&self) -> u32 {
+        INPUT_WINDOW_SECONDS * self.tick_rate
+    }
+
+    /// Settings a new room starts out with.
+    pub fn room_settings(&self) -> RoomSettings {
+        RoomSettings {
+            countdown_ms: self.countdown_ms,
+            max_racers: self.max_players,
+            environment: self.environment.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Connections the transport must make room for: every racer and
+    /// spectator of every room.
+    pub fn max_clients(&self) -> usize {
+        (self.max_players + MAX_SPECTATORS).saturating_mul(self.max_rooms)
+    }
+
+    /// Messages a client may send per second: an input every tick and an
+    /// ack every snapshot, with as much again to spare.
+    pub fn max_messages_per_second(&self) -> u32 {
+        2 * (self.tick_rate + self.snapshot_rate)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check(
+            (1..=64).contains(&self.max_players),
+            "max_players",
+            "between 1 and 64",
+            self.max_players,
+        )?;
+        check(
+            self.max_rooms >= 1,
+            "max_rooms",
+            "at least 1",
+            self.max_rooms,
+        )?;
+        check(
+            self.max_clients() <= MAX_CLIENTS,
+            "max_rooms",
+            "small enough that (max_players + 32 spectators) * max_rooms is at most 1024",
+            self.max_rooms,
+        )?;
+        check(
+            (10..=240).contains(&self.tick_rate),
+            "tick_rate",
+            "between 10 and 240",
+            self.tick_rate,
+        )?;
+        check(
+            self.snapshot_rate >= 1
+                && self.snapshot_rate <= self.tick_rate
+                && self.tick_rate.is_multiple_of(self.snapshot_rate),
+            "snapshot_rate",
+            "a divisor of tick_rate",
+            self.snapshot_rate,
+        )?;
+        check(
+            (1..=60_000).contains(&self.countdown_ms),
+            "countdown_ms",
+            "between 1 and 60000",
+            self.countdown_ms,
+        )?;
+        check(
+            self.input_buffer.capacity >= self.input_buffer.delay.max(1) as usize,
+            "input_buffer.capacity",
+            "at least input_buffer.delay",
+            self.input_buffer.capacity,
+        )?;
+        if let Err(err) = self.auth.private_key() {
+            return Err(ConfigError::Invalid {
+                field: "auth.private_key",
+                expected: "a hex key when auth.secure is set",
+                got: err.to_string(),
+            });
+        }
+        self.network.validate()?;
+        self.environment.validate()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    pub server_addr: SocketAddr,
+    /// Random when not given.
+    pub name: Option<String>,
+    /// Room to join; a new one is created when not given.
+    pub room_code: Option<String>,
+    pub spectate: bool,
+    /// Connect token file; when set the client connects securely.
+    pub connect_token: Option<PathBuf>,
+    /// Race on a server run inside the client instead of `server_addr`.
+    pub offline: bool,
+    /// Impairments applied both ways to the offline connection.
+    pub network: NetworkConditions,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            server_addr: (Ipv4Addr::LOCALHOST, DEFAULT_PORT).into(),
+            name: None,
+            room_code: None,
+            spectate: false,
+            connect_token: None,
+            offline: false,
+            network: NetworkConditions::default(),
+        }
+    }
+}
+
+impl ClientSettings {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check(
+            !self.server_addr.ip().is_unspecified(),
+            "server_addr",
+            "a specific address",
+            self.server_addr,
+        )?;
+        if let Some(name) = &self.name {
+            check(
+                validate_name(name).is_some(),
+                "name",
+                "up to 24 letters, digits, spaces, '-', '_' or '.'",
+                name,
+            )?;
+        }
+        if let Some(code) = &self.room_code {
+            check(
+                !code.is_empty()
+                    && code.len() <= 8
+                    && code.chars().all(|c| c.is_ascii_alphanumeric()),
+                "room_code",
+                "up to 8 letters or digits",
+                code,
+            )?;
+        }
+        self.network.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_file_keeps_defaults() {
+        let settings: ServerSettings = toml::from_str(
+            r#"
+            bind_addr = "127.0.0.1:6000"
+            tick_rate = 30
+            snapshot_rate = 10
+
+            [input_buffer]
+            delay = 3
+
+            [network]
+            latency_ms = 80
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.bind_addr.port(), 6000);
+        assert_eq!(settings.snapshot_interval(), 3);
+        assert_eq!(settings.input_buffer.delay, 3);
+        assert_eq!(settings.input_buffer.capacity, 16);
+        assert_eq!(settings.max_players, MAX_PLAYERS);
+        assert_eq!(settings.network.latency_ms, 80);
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<ClientSettings>("sever_addr = \"1.2.3.4:5000\"").is_err());
+    }
+
+    #[test]
+    fn invalid_values_name_the_field() {
+        let settings = ServerSettings {
+            snapshot_rate: 25,
+            ..Default::default()
+        };
+        let err = settings.validate().unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                field: "snapshot_rate",
+                ..
+            }
+        ));
+
+        for settings in [
+            ServerSettings {
+                max_players: 33,
+                ..Default::default()
+            },
+            ServerSettings {
+                max_rooms: 26,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                settings.validate(),
+                Err(ConfigError::Invalid {
+                    field: "max_rooms",
+                    ..
+                })
+            ));
+        }
+        let no_countdown = ServerSettings {
+            countdown_ms: 0,
+            ..Default::default()
+        };
+        assert!(no_countdown.validate().is_err());
+
+        let client = ClientSettings {
+            room_code: Some("AB-C".into()),
+            ..Default::default()
+        };
+        assert!(client.validate().is_err());
+    }
+
+    #[test]
+    fn secure_auth_needs_a_key() {
+        let mut settings: ServerSettings = toml::from_str("[auth]\nsecure = true").unwrap();
+        assert!(matches!(
+            settings.validate(),
+            Err(ConfigError::Invalid {
+                field: "auth.private_key",
+                ..
+            })
+        ));
+
+        settings.auth.private_key = Some("ab".repeat(PRIVATE_KEY_BYTES));
+        settings.validate().unwrap();
+        assert_eq!(
+            settings.auth.private_key(),
+            Ok(Some([0xab; PRIVATE_KEY_BYTES]))
+        );
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        ServerSettings::default().validate().unwrap();
+        ClientSettings::default().validate().unwrap();
+    }
+}
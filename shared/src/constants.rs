@@ -3,10 +3,17 @@ use glam::Vec3;
 pub const TICK_RATE: u32 = 60;
 pub const SNAPSHOT_RATE: u32 = 20;
 pub const PROTOCOL_ID: u64 = 7_812_345_678_901;
+/// Bump whenever `ClientMessage` or `ServerMessage` change shape.
+pub const PROTOCOL_VERSION: u32 = 16;
+pub const BUILD: &str = env!("CARGO_PKG_VERSION");
 pub const MAX_PLAYERS: usize = 8;
+pub const COUNTDOWN_MS: u32 = 3_000;
+/// Spectators per room; they don't count towards `MAX_PLAYERS`.
+pub const MAX_SPECTATORS: usize = 32;
 pub const TRACK_LENGTH: f32 = 3600.0;
 pub const BOOST_COST: f32 = 35.0;
 pub const BOOST_REGEN: f32 = 15.0;
+pub const BUMP_COST: f32 = 5.0;
 pub const BASE_SPEED: f32 = 250.0;
 pub const BOOST_SPEED: f32 = 420.0;
 pub const PLAYER_RADIUS: f32 = 14.0;
@@ -17,3 +24,12 @@ pub const REGION_NAMES: [&str; 6] = ["Vagina", "Cervix", "Uterus", "UTJ", "Tube"
 pub fn start_position() -> Vec3 {
     Vec3::new(-100.0, 0.0, 0.0)
 }
+
+/// Starting spot of the racer in `slot`, in rows of five behind the line
+/// so that nobody starts out overlapping anyone else.
+pub fn start_grid_position(slot: usize) -> Vec3 {
+    let spacing = PLAYER_RADIUS * 2.5;
+    let row = (slot / 5) as f32;
+    let column = (slot % 5) as f32 - 2.0;
+    start_position() + Vec3::new(-row * spacing, 0.0, column * spacing)
+}
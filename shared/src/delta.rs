@@ -0,0 +1,146 @@
+use crate::{EntityDelta, EntitySnapshot};
+
+/// Number of past snapshots the server keeps as potential baselines.
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+/// Describes `current` relative to `baseline`: the fields that changed per
+/// entity, and the ids of entities that are gone.
+pub fn diff_snapshots(
+    baseline: &[EntitySnapshot],
+    current: &[EntitySnapshot],
+) -> (Vec<EntityDelta>, Vec<u64>) {
+    let changed = current
+        .iter()
+        .filter_map(|entity| {
+            let Some(old) = baseline.iter().find(|b| b.id == entity.id) else {
+                return Some(EntityDelta {
+                    id: entity.id,
+                    position: Some(entity.position),
+                    velocity: Some(entity.velocity),
+                    stamina: Some(entity.stamina),
+                    region: Some(entity.region),
+                });
+            };
+            let delta = EntityDelta {
+                id: entity.id,
+                position: (old.position != entity.position).then_some(entity.position),
+                velocity: (old.velocity != entity.velocity).then_some(entity.velocity),
+                stamina: (old.stamina != entity.stamina).then_some(entity.stamina),
+                region: (old.region != entity.region).then_some(entity.region),
+            };
+            let unchanged = delta.position.is_none()
+                && delta.velocity.is_none()
+                && delta.stamina.is_none()
+                && delta.region.is_none();
+            (!unchanged).then_some(delta)
+        })
+        .collect();
+
+    let removed = baseline
+        .iter()
+        .filter(|b| !current.iter().any(|c| c.id == b.id))
+        .map(|b| b.id)
+        .collect();
+
+    (changed, removed)
+}
+
+/// Rebuilds the full snapshot described by a delta. Returns `None` when the
+/// delta introduces an entity without sending all of its fields.
+pub fn apply_delta(
+    baseline: &[EntitySnapshot],
+    changed: &[EntityDelta],
+    removed: &[u64],
+) -> Option<Vec<EntitySnapshot>> {
+    let mut entities: Vec<EntitySnapshot> = baseline
+        .iter()
+        .filter(|b| !removed.contains(&b.id))
+        .cloned()
+        .collect();
+
+    for delta in changed {
+        match entities.iter_mut().find(|e| e.id == delta.id) {
+            Some(entity) => {
+                if let Some(position) = delta.position {
+                    entity.position = position;
+                }
+                if let Some(velocity) = delta.velocity {
+                    entity.velocity = velocity;
+                }
+                if let Some(stamina) = delta.stamina {
+                    entity.stamina = stamina;
+                }
+                if let Some(region) = delta.region {
+                    entity.region = region;
+                }
+            }
+            None => entities.push(EntitySnapshot {
+                id: delta.id,
+                position: delta.position?,
+                velocity: delta.velocity?,
+                stamina: delta.stamina?,
+                region: delta.region?,
+            }),
+        }
+    }
+
+    Some(entities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RegionId;
+
+    fn entity(id: u64, x: i16) -> EntitySnapshot {
+        EntitySnapshot {
+            id,
+            position: [x, 0, 0],
+            velocity: [4000, 0, 0],
+            stamina: 200,
+            region: RegionId::Vagina,
+        }
+    }
+
+    #[test]
+    fn only_changed_fields_are_sent() {
+        let baseline = vec![entity(1, 0), entity(2, 0)];
+        let current = vec![entity(1, 40), entity(2, 0)];
+        let (changed, removed) = diff_snapshots(&baseline, &current);
+
+        assert!(removed.is_empty());
+        assert_eq!(
+            changed,
+            vec![EntityDelta {
+                id: 1,
+                position: Some([40, 0, 0]),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn delta_round_trip_with_joins_and_leaves() {
+        let baseline = vec![entity(1, 0), entity(2, 80)];
+        let mut moved = entity(1, 320);
+        moved.stamina = 160;
+        moved.region = RegionId::Cervix;
+        let current = vec![moved, entity(3, 0)];
+
+        let (changed, removed) = diff_snapshots(&baseline, &current);
+        assert_eq!(removed, vec![2]);
+
+        let rebuilt = apply_delta(&baseline, &changed, &removed).unwrap();
+        assert_eq!(rebuilt, current);
+    }
+
+    #[test]
+    fn rejects_partial_new_entity() {
+        let changed = vec![EntityDelta {
+            id: 9,
+            position: Some([0; 3]),
+            ..Default::default()
+        }];
+        assert!(apply_delta(&[], &changed, &[]).is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::check, ConfigError, PlayerKinematics, RegionId};
+
+/// A periodic squeeze of the walls. Each pulse lasts `duration_ms` out of
+/// every `period_ms`, and every other pulse pushes the opposite way, so
+/// contractions help as often as they hinder.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Contraction {
+    pub period_ms: u32,
+    pub duration_ms: u32,
+    /// Acceleration during a forward pulse.
+    pub push: Vec3,
+}
+
+impl Contraction {
+    /// Acceleration `elapsed_ms` into the clock.
+    pub fn push_at(&self, elapsed_ms: u64) -> Vec3 {
+        let period = u64::from(self.period_ms.max(1));
+        if elapsed_ms % period >= u64::from(self.duration_ms) {
+            Vec3::ZERO
+        } else if (elapsed_ms / period).is_multiple_of(2) {
+            self.push
+        } else {
+            -self.push
+        }
+    }
+}
+
+/// What a region's surroundings do to a swimmer. Keys left out of a
+/// region's table in a config file have no effect.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegionEffects {
+    /// Drag per second per unit of speed, on top of the room's handling.
+    pub drag: f32,
+    /// Acceleration from the fluid moving through the region.
+    pub flow: Vec3,
+    pub contraction: Option<Contraction>,
+    /// Stamina burnt per second, on top of whatever boosting costs.
+    pub damage: f32,
+}
+
+impl RegionEffects {
+    /// One tick of the region acting on `kin`, `race_tick` ticks into the
+    /// room's race. Pulses are timed off the room, not the racer, so every
+    /// racer feels the same ones.
+    pub fn apply(&self, mut kin: PlayerKinematics, race_tick: u32, dt: f32) -> PlayerKinematics {
+        let mut accel = self.flow;
+        if let Some(contraction) = &self.contraction {
+            let elapsed_ms = (f64::from(race_tick) * f64::from(dt) * 1000.0) as u64;
+            accel += contraction.push_at(elapsed_ms);
+        }
+        kin.velocity = kin.velocity * (1.0 - self.drag * dt).max(0.0) + accel * dt;
+        kin.stamina = (kin.stamina - self.damage * dt).max(0.0);
+        kin
+    }
+
+    /// Checks the effects of `region`, naming it in any error.
+    fn validate(&self, region: &'static str) -> Result<(), ConfigError> {
+        self.check().map_err(|source| ConfigError::InRegion {
+            region,
+            source: Box::new(source),
+        })
+    }
+
+    fn check(&self) -> Result<(), ConfigError> {
+        check(
+            (0.0..=10.0).contains(&self.drag),
+            "drag",
+            "between 0 and 10",
+            self.drag,
+        )?;
+        check(
+            self.flow.length() <= 1000.0,
+            "flow",
+            "at most 1000 long",
+            self.flow,
+        )?;
+        check(
+            (0.0..=1000.0).contains(&self.damage),
+            "damage",
+            "between 0 and 1000",
+            self.damage,
+        )?;
+        let Some(contraction) = &self.contraction else {
+            return Ok(());
+        };
+        check(
+            (100..=60_000).contains(&contraction.period_ms),
+            "contraction.period_ms",
+            "between 100 and 60000",
+            contraction.period_ms,
+        )?;
+        check(
+            contraction.duration_ms <= contraction.period_ms,
+            "contraction.duration_ms",
+            "at most period_ms",
+            contraction.duration_ms,
+        )?;
+        check(
+            contraction.push.length() <= 2000.0,
+            "contraction.push",
+            "at most 2000 long",
+            contraction.push,
+        )
+    }
+}
+
+/// Effects of every region along the track. The defaults play out what
+/// `tooltips()` describes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Environment {
+    pub vagina: RegionEffects,
+    pub cervix: RegionEffects,
+    pub uterus: RegionEffects,
+    pub utj: RegionEffects,
+    pub tube: RegionEffects,
+    pub ampulla: RegionEffects,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            // Acid wears swimmers down
+            vagina: RegionEffects {
+                damage: 4.0,
+                ..Default::default()
+            },
+            // Mucus strands hold everyone back
+            cervix: RegionEffects {
+                drag: 1.0,
+                ..Default::default()
+            },
+            uterus: RegionEffects {
+                contraction: Some(Contraction {
+                    period_ms: 4_000,
+                    duration_ms: 800,
+                    push: Vec3::new(300.0, 0.0, 0.0),
+                }),
+                ..Default::default()
+            },
+            // The gate is a squeeze as well as narrow
+            utj: RegionEffects {
+                drag: 1.5,
+                ..Default::default()
+            },
+            // Flow runs towards the ampulla
+            tube: RegionEffects {
+                flow: Vec3::new(60.0, 0.0, 0.0),
+                ..Default::default()
+            },
+            ampulla: RegionEffects::default(),
+        }
+    }
+}
+
+impl Environment {
+    pub fn region(&self, id: RegionId) -> &RegionEffects {
+        match id {
+            RegionId::Vagina => &self.vagina,
+            RegionId::Cervix => &self.cervix,
+            RegionId::Uterus => &self.uterus,
+            RegionId::Utj => &self.utj,
+            RegionId::Tube => &self.tube,
+            RegionId::Ampulla => &self.ampulla,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (region, effects) in [
+            ("vagina", &self.vagina),
+            ("cervix", &self.cervix),
+            ("uterus", &self.uterus),
+            ("utj", &self.utj),
+            ("tube", &self.tube),
+            ("ampulla", &self.ampulla),
+        ] {
+            effects.validate(region)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{simulate_tick, InputFrame, RoomSettings, BOOST_REGEN, TICK_RATE};
+
+    const DT: f32 = 1.0 / TICK_RATE as f32;
+
+    fn cruising() -> PlayerKinematics {
+        PlayerKinematics {
+            velocity: Vec3::new(200.0, 0.0, 0.0),
+            ..PlayerKinematics::spawn(Vec3::ZERO)
+        }
+    }
+
+    #[test]
+    fn mucus_drags_and_flow_carries() {
+        let environment = Environment::default();
+        let held = environment.cervix.apply(cruising(), 0, DT);
+        assert!(held.velocity.x < 200.0);
+
+        let still = PlayerKinematics::spawn(Vec3::ZERO);
+        let carried = environment.tube.apply(still, 0, DT);
+        assert!(carried.velocity.x > 0.0);
+
+        let calm = environment
+            .region(RegionId::Ampulla)
+            .apply(cruising(), 0, DT);
+        assert_eq!(calm.velocity, cruising().velocity);
+    }
+
+    #[test]
+    fn contractions_pulse_one_way_then_the_other() {
+        let contraction = Contraction {
+            period_ms: 1_000,
+            duration_ms: 200,
+            push: Vec3::X,
+        };
+        assert_eq!(contraction.push_at(100), Vec3::X);
+        assert_eq!(contraction.push_at(500), Vec3::ZERO);
+        assert_eq!(contraction.push_at(1_100), -Vec3::X);
+        assert_eq!(contraction.push_at(2_100), Vec3::X);
+
+        let uterus = RegionEffects {
+            contraction: Some(contraction),
+            ..Default::default()
+        };
+        let pushed = uterus.apply(cruising(), 0, DT);
+        let resting = uterus.apply(cruising(), TICK_RATE / 2, DT);
+        assert!(pushed.velocity.x > resting.velocity.x);
+    }
+
+    #[test]
+    fn acid_burns_stamina_regen_would_restore() {
+        let settings = RoomSettings::default();
+        let mut kin = PlayerKinematics {
+            stamina: 50.0,
+            ..PlayerKinematics::spawn(Vec3::new(100.0, 0.0, 0.0))
+        };
+        let coast = InputFrame::default();
+        for race_tick in 0..TICK_RATE {
+            kin = simulate_tick(kin, &coast, DT, &settings, race_tick);
+        }
+        let expected = 50.0 + BOOST_REGEN - settings.environment.vagina.damage;
+        assert!((kin.stamina - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn effects_come_from_the_config_file() {
+        let environment: Environment = toml::from_str(
+            r#"
+            [tube]
+            flow = [120.0, 0.0, 0.0]
+
+            [uterus.contraction]
+            period_ms = 2000
+            duration_ms = 3000
+            push = [100.0, 0.0, 0.0]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(environment.tube.flow.x, 120.0);
+        assert_eq!(environment.cervix, Environment::default().cervix);
+        assert!(matches!(
+            environment.validate(),
+            Err(ConfigError::InRegion { region: "uterus", source })
+                if matches!(*source, ConfigError::Invalid {
+                    field: "contraction.duration_ms",
+                    ..
+                })
+        ));
+        Environment::default().validate().unwrap();
+    }
+}
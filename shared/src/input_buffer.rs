@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{InputFrame, TICK_RATE};
+
+/// Frames repeated in every `ClientMessage::Inputs`.
+pub const INPUT_REDUNDANCY: usize = 5;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct InputBufferConfig {
+    /// Frames collected before the first one is consumed, absorbing jitter.
+    pub delay: u32,
+    /// Frames held at most; beyond this the oldest are dropped to catch up.
+    pub capacity: usize,
+    /// Missing ticks filled by repeating the last input before going neutral.
+    pub max_repeat: u32,
+}
+
+impl Default for InputBufferConfig {
+    fn default() -> Self {
+        Self {
+            delay: 2,
+            capacity: 16,
+            max_repeat: 4,
+        }
+    }
+}
+
+/// Per-player queue of inputs keyed by `InputFrame::tick`, drained one frame
+/// per server tick so movement doesn't depend on packet timing.
+#[derive(Debug, Clone)]
+pub struct InputBuffer {
+    config: InputBufferConfig,
+    frames: BTreeMap<u32, InputFrame>,
+    /// Client tick consumed by the next `pop`, once buffering has started.
+    next_tick: Option<u32>,
+    last: InputFrame,
+    repeats: u32,
+    /// Frames discarded as late, duplicated or overflowing.
+    pub dropped: u64,
+    /// Ticks filled in because their frame never arrived in time.
+    pub missed: u64,
+}
+
+impl InputBuffer {
+    pub fn new(config: InputBufferConfig) -> Self {
+        Self {
+            config,
+            frames: BTreeMap::new(),
+            next_tick: None,
+            last: InputFrame::default(),
+            repeats: 0,
+            dropped: 0,
+            missed: 0,
+        }
+    }
+
+    /// Highest client tick consumed so far.
+    pub fn last_consumed(&self) -> Option<u32> {
+        self.next_tick.map(|tick| tick.wrapping_sub(1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Queues a frame. Returns `false` if it was dropped as stale or duplicate.
+    pub fn push(&mut self, frame: InputFrame) -> bool {
+        let stale = self.next_tick.is_some_and(|next| frame.tick < next);
+        if stale || self.frames.contains_key(&frame.tick) {
+            self.dropped += 1;
+            return false;
+        }
+        self.frames.insert(frame.tick, frame);
+
+        while self.frames.len() > self.config.capacity {
+            self.frames.pop_first();
+            self.dropped += 1;
+            self.next_tick = self.frames.keys().next().copied();
+        }
+        true
+    }
+
+    /// Input to simulate for the current server tick.
+    pub fn pop(&mut self) -> InputFrame {
+        let next = match self.next_tick {
+            Some(next) => next,
+            None => {
+                if (self.frames.len() as u32) < self.config.delay.max(1) {
+                    return InputFrame::default();
+                }
+                *self.frames.keys().next().unwrap()
+            }
+        };
+        self.next_tick = Some(next.wrapping_add(1));
+
+        if let Some(frame) = self.frames.remove(&next) {
+            self.repeats = 0;
+            self.last = frame.clone();
+            return frame;
+        }
+
+        self.missed += 1;
+        if self.repeats < self.config.max_repeat {
+            self.repeats += 1;
+            InputFrame {
+                tick: next,
+                ..self.last.clone()
+            }
+        } else {
+            InputFrame {
+                tick: next,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+impl Default for InputBuffer {
+    fn default() -> Self {
+        Self::new(InputBufferConfig::default())
+    }
+}
+
+/// Client-side record of inputs sent but not yet acknowledged by the server.
+#[derive(Debug, Clone)]
+pub struct InputHistory {
+    frames: VecDeque<InputFrame>,
+    /// Frames kept at most while the server isn't acknowledging any.
+    max_frames: usize,
+}
+
+impl InputHistory {
+    /// Keeps two seconds' worth of frames at `tick_rate`.
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            max_frames: 2 * tick_rate as usize,
+        }
+    }
+
+    pub fn record(&mut self, frame: InputFrame) {
+        if self.frames.len() >= self.max_frames {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// Forgets every frame up to and including the acknowledged tick.
+    pub fn ack(&mut self, tick: u32) {
+        while self.frames.front().is_some_and(|f| f.tick <= tick) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// The newest `count` unacknowledged frames, oldest first.
+    pub fn latest(&self, count: usize) -> Vec<InputFrame> {
+        let skip = self.frames.len().saturating_sub(count);
+        self.frames.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn unacked(&self) -> impl Iterator<Item = &InputFrame> {
+        self.frames.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl Default for InputHistory {
+    fn default() -> Self {
+        Self::new(TICK_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(tick: u32) -> InputFrame {
+        InputFrame {
+            tick,
+            up: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn waits_for_delay_then_plays_in_tick_order() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(frame(11));
+        assert_eq!(buffer.pop().tick, 0);
+
+        buffer.push(frame(10));
+        assert_eq!(buffer.pop().tick, 10);
+        assert_eq!(buffer.pop().tick, 11);
+        assert_eq!(buffer.last_consumed(), Some(11));
+    }
+
+    #[test]
+    fn drops_duplicates_and_late_frames() {
+        let mut buffer = InputBuffer::default();
+        assert!(buffer.push(frame(1)));
+        assert!(!buffer.push(frame(1)));
+        buffer.push(frame(2));
+        buffer.pop();
+        buffer.pop();
+        assert!(!buffer.push(frame(1)));
+        assert_eq!(buffer.dropped, 2);
+    }
+
+    #[test]
+    fn repeats_then_goes_neutral_when_frames_are_missing() {
+        let config = InputBufferConfig {
+            delay: 1,
+            max_repeat: 2,
+            ..Default::default()
+        };
+        let mut buffer = InputBuffer::new(config);
+        buffer.push(frame(1));
+        assert!(buffer.pop().up);
+
+        let repeated: Vec<_> = (0..3).map(|_| buffer.pop()).collect();
+        assert_eq!(
+            repeated.iter().map(|f| f.tick).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert!(repeated[0].up && repeated[1].up);
+        assert!(!repeated[2].up);
+        assert_eq!(buffer.missed, 3);
+
+        // The real frame for a filled-in tick arrives too late
+        assert!(!buffer.push(frame(3)));
+    }
+
+    #[test]
+    fn redundant_frames_survive_a_lost_packet() {
+        let mut history = InputHistory::default();
+        let mut buffer = InputBuffer::default();
+        for tick in 1..=3 {
+            history.record(frame(tick));
+            let packet = history.latest(INPUT_REDUNDANCY);
+            // The packet carrying tick 2 is lost
+            if tick != 2 {
+                for f in packet {
+                    buffer.push(f);
+                }
+            }
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.dropped, 1);
+
+        history.ack(2);
+        assert_eq!(history.latest(INPUT_REDUNDANCY).len(), 1);
+    }
+
+    #[test]
+    fn history_spans_the_same_time_at_any_tick_rate() {
+        for tick_rate in [30, TICK_RATE, 240] {
+            let mut history = InputHistory::new(tick_rate);
+            for tick in 1..=10 * tick_rate {
+                history.record(frame(tick));
+            }
+            assert_eq!(history.len(), 2 * tick_rate as usize);
+        }
+    }
+
+    #[test]
+    fn overflow_skips_ahead() {
+        let config = InputBufferConfig {
+            delay: 1,
+            capacity: 3,
+            ..Default::default()
+        };
+        let mut buffer = InputBuffer::new(config);
+        for tick in 1..=5 {
+            buffer.push(frame(tick));
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop().tick, 3);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use glam::Vec3;
+
+use crate::TICK_RATE;
+
+/// Remote entities are drawn this many ticks behind the newest snapshot, so
+/// there is nearly always a later sample to interpolate towards.
+pub const INTERPOLATION_DELAY_TICKS: f64 = 6.0;
+/// How far past the newest sample an entity may be extrapolated when
+/// snapshots are late, in ticks.
+pub const MAX_EXTRAPOLATION_TICKS: f64 = 6.0;
+
+/// Entity state at a server tick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionSample {
+    pub tick: u32,
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+/// Time-ordered samples of one entity's motion.
+#[derive(Debug, Clone, Default)]
+pub struct InterpolationBuffer {
+    samples: VecDeque<MotionSample>,
+}
+
+impl InterpolationBuffer {
+    const CAPACITY: usize = 32;
+
+    /// Adds a sample. Duplicates and samples older than the newest one are
+    /// rejected, so late packets can't pull an entity backwards.
+    pub fn push(&mut self, sample: MotionSample) -> bool {
+        if self
+            .newest_tick()
+            .is_some_and(|newest| sample.tick <= newest)
+        {
+            return false;
+        }
+        if self.samples.len() == Self::CAPACITY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        true
+    }
+
+    pub fn newest_tick(&self) -> Option<u32> {
+        self.samples.back().map(|s| s.tick)
+    }
+
+    /// Position and velocity at a fractional server tick: interpolated
+    /// between the samples around it, or extrapolated a little from the
+    /// newest one.
+    pub fn sample(&self, tick: f64, tick_rate: u32) -> Option<(Vec3, Vec3)> {
+        let first = self.samples.front()?;
+        let newest = self.samples.back()?;
+
+        if tick <= f64::from(first.tick) {
+            return Some((first.position, first.velocity));
+        }
+        if tick >= f64::from(newest.tick) {
+            let ahead = (tick - f64::from(newest.tick)).min(MAX_EXTRAPOLATION_TICKS);
+            let seconds = (ahead / f64::from(tick_rate)) as f32;
+            return Some((newest.position + newest.velocity * seconds, newest.velocity));
+        }
+
+        let (a, b) = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .find(|(_, b)| f64::from(b.tick) >= tick)?;
+        let t = ((tick - f64::from(a.tick)) / f64::from(b.tick - a.tick)) as f32;
+        Some((
+            a.position.lerp(b.position, t),
+            a.velocity.lerp(b.velocity, t),
+        ))
+    }
+}
+
+/// Fractional server tick at which remote entities are rendered. It runs at
+/// local speed and is nudged towards `newest - INTERPOLATION_DELAY_TICKS`.
+#[derive(Debug, Clone)]
+pub struct InterpolationClock {
+    tick_rate: u32,
+    tick: Option<f64>,
+}
+
+impl Default for InterpolationClock {
+    fn default() -> Self {
+        Self::new(TICK_RATE)
+    }
+}
+
+impl InterpolationClock {
+    /// Beyond this many ticks off target the clock jumps instead of drifting.
+    const SNAP_TICKS: f64 = 30.0;
+    /// Fraction of the remaining error corrected per update.
+    const DRIFT_RATE: f64 = 0.05;
+
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            tick_rate,
+            tick: None,
+        }
+    }
+
+    pub fn advance(&mut self, dt_seconds: f64, newest_tick: u32) -> f64 {
+        let target = f64::from(newest_tick) - INTERPOLATION_DELAY_TICKS;
+        let tick = match self.tick {
+            Some(tick) => {
+                let tick = tick + dt_seconds * f64::from(self.tick_rate);
+                if (target - tick).abs() > Self::SNAP_TICKS {
+                    target
+                } else {
+                    tick + (target - tick) * Self::DRIFT_RATE
+                }
+            }
+            None => target,
+        };
+        self.tick = Some(tick);
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(tick: u32, x: f32) -> MotionSample {
+        MotionSample {
+            tick,
+            position: Vec3::new(x, 0.0, 0.0),
+            velocity: Vec3::new(TICK_RATE as f32, 0.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let mut buffer = InterpolationBuffer::default();
+        buffer.push(sample(10, 0.0));
+        buffer.push(sample(12, 20.0));
+
+        let (pos, _) = buffer.sample(11.0, TICK_RATE).unwrap();
+        assert!((pos.x - 10.0).abs() < 1e-4);
+        let (pos, _) = buffer.sample(9.0, TICK_RATE).unwrap();
+        assert_eq!(pos.x, 0.0);
+    }
+
+    #[test]
+    fn rejects_out_of_order_and_duplicate_samples() {
+        let mut buffer = InterpolationBuffer::default();
+        assert!(buffer.push(sample(10, 0.0)));
+        assert!(!buffer.push(sample(10, 5.0)));
+        assert!(buffer.push(sample(12, 20.0)));
+        assert!(!buffer.push(sample(11, 99.0)));
+        assert_eq!(buffer.newest_tick(), Some(12));
+    }
+
+    #[test]
+    fn extrapolation_is_limited() {
+        let mut buffer = InterpolationBuffer::default();
+        buffer.push(sample(10, 0.0));
+
+        // Velocity is one unit per tick
+        let (pos, _) = buffer.sample(12.0, TICK_RATE).unwrap();
+        assert!((pos.x - 2.0).abs() < 1e-4);
+        let (pos, _) = buffer.sample(100.0, TICK_RATE).unwrap();
+        assert!((pos.x - MAX_EXTRAPOLATION_TICKS as f32).abs() < 1e-4);
+    }
+
+    #[test]
+    fn clock_trails_newest_tick() {
+        let mut clock = InterpolationClock::default();
+        assert_eq!(clock.advance(0.0, 100), 100.0 - INTERPOLATION_DELAY_TICKS);
+
+        let dt = 1.0 / f64::from(TICK_RATE);
+        let mut tick = 0.0;
+        for newest in 101..400 {
+            tick = clock.advance(dt, newest);
+        }
+        assert!((tick - (399.0 - INTERPOLATION_DELAY_TICKS)).abs() < 0.5);
+
+        // A long stall snaps instead of crawling
+        assert_eq!(clock.advance(dt, 2000), 2000.0 - INTERPOLATION_DELAY_TICKS);
+    }
+}
@@ -1,9 +1,35 @@
+pub mod auth;
+pub mod channels;
+pub mod clock;
+pub mod conditioner;
+pub mod config;
 pub mod constants;
+pub mod delta;
+pub mod environment;
+pub mod input_buffer;
+pub mod interpolation;
+pub mod loopback;
 pub mod messages;
 pub mod movement;
 pub mod region;
+pub mod relay;
+pub mod room_settings;
+pub mod validation;
 
+pub use auth::*;
+pub use channels::*;
+pub use clock::*;
+pub use conditioner::*;
+pub use config::*;
 pub use constants::*;
+pub use delta::*;
+pub use environment::*;
+pub use input_buffer::*;
+pub use interpolation::*;
+pub use loopback::*;
 pub use messages::*;
 pub use movement::*;
 pub use region::*;
+pub use relay::*;
+pub use room_settings::*;
+pub use validation::*;
@@ -0,0 +1,377 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use renet::{RenetClient, RenetServer};
+
+use crate::{NetworkConditions, PacketConditioner};
+
+/// In-process connections between one server and any number of clients.
+/// Packets are delivered whole, in order and without delay on the next
+/// transport update, unless either end sets `NetworkConditions`. Either
+/// way a game run over it is deterministic.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackHub {
+    links: Arc<Mutex<Links>>,
+}
+
+#[derive(Debug, Default)]
+struct Links {
+    last_client_id: u64,
+    open: HashMap<u64, Link>,
+}
+
+#[derive(Debug, Default)]
+struct Link {
+    to_server: VecDeque<Vec<u8>>,
+    to_client: VecDeque<Vec<u8>>,
+    /// Set by whichever side hangs up; the other side drops the link.
+    closed: bool,
+}
+
+impl LoopbackHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a connection under a fresh client id. The server sees it on
+    /// its next update.
+    pub fn connect(&self) -> LoopbackClientTransport {
+        let client_id = {
+            let mut links = self.lock();
+            links.last_client_id += 1;
+            links.last_client_id
+        };
+        self.reconnect(client_id)
+    }
+
+    /// Opens a connection under `client_id`, as a client reconnecting with
+    /// a connect token keeps its id. Any link still open under it is cut.
+    pub fn reconnect(&self, client_id: u64) -> LoopbackClientTransport {
+        self.lock().open.insert(client_id, Link::default());
+        LoopbackClientTransport {
+            hub: self.clone(),
+            client_id,
+            path: Conditioners::new(NetworkConditions::default(), client_id),
+        }
+    }
+
+    pub fn server(&self) -> LoopbackServerTransport {
+        LoopbackServerTransport {
+            hub: self.clone(),
+            conditions: NetworkConditions::default(),
+            connected: HashMap::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Links> {
+        self.links.lock().unwrap()
+    }
+}
+
+/// Conditioners for both directions of one connection, at one end of it.
+#[derive(Debug)]
+pub(crate) struct Conditioners {
+    pub(crate) incoming: PacketConditioner,
+    pub(crate) outgoing: PacketConditioner,
+}
+
+impl Conditioners {
+    pub(crate) fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        Self {
+            incoming: PacketConditioner::new(conditions, seed),
+            outgoing: PacketConditioner::new(conditions, !seed),
+        }
+    }
+
+    pub(crate) fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.incoming.set_conditions(conditions);
+        self.outgoing.set_conditions(conditions);
+    }
+
+    pub(crate) fn advance(&mut self, dt: Duration) {
+        self.incoming.advance(dt);
+        self.outgoing.advance(dt);
+    }
+}
+
+/// Server end of a `LoopbackHub`, used in place of `NetcodeServerTransport`.
+#[derive(Debug)]
+pub struct LoopbackServerTransport {
+    hub: LoopbackHub,
+    conditions: NetworkConditions,
+    connected: HashMap<u64, Conditioners>,
+}
+
+impl LoopbackServerTransport {
+    pub fn conditions(&self) -> NetworkConditions {
+        self.conditions
+    }
+
+    /// Impairs traffic both ways on every connection, current and future.
+    pub fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.conditions = conditions;
+        for path in self.connected.values_mut() {
+            path.set_conditions(conditions);
+        }
+    }
+
+    /// Accepts new connections, drops closed ones and hands the server every
+    /// packet from clients that is due by now.
+    pub fn update(&mut self, dt: Duration, server: &mut RenetServer) {
+        let mut links = self.hub.lock();
+        for client_id in server.disconnections_id() {
+            if let Some(link) = links.open.get_mut(&client_id) {
+                link.closed = true;
+            }
+            self.connected.remove(&client_id);
+            server.remove_connection(client_id);
+        }
+
+        let mut hung_up = Vec::new();
+        for (&client_id, link) in links.open.iter_mut() {
+            if link.closed {
+                hung_up.push(client_id);
+                continue;
+            }
+            let path = self.connected.entry(client_id).or_insert_with(|| {
+                server.add_connection(client_id);
+                Conditioners::new(self.conditions, client_id)
+            });
+            path.advance(dt);
+            for packet in link.to_server.drain(..) {
+                path.incoming.send(packet);
+            }
+            for packet in path.incoming.receive() {
+                // Only fails for unknown clients, which we just added
+                let _ = server.process_packet_from(&packet, client_id);
+            }
+        }
+        for client_id in hung_up {
+            links.open.remove(&client_id);
+            if self.connected.remove(&client_id).is_some() {
+                server.remove_connection(client_id);
+            }
+        }
+    }
+
+    pub fn send_packets(&mut self, server: &mut RenetServer) {
+        let mut links = self.hub.lock();
+        for (client_id, path) in self.connected.iter_mut() {
+            let (Some(link), Ok(packets)) = (
+                links.open.get_mut(client_id),
+                server.get_packets_to_send(*client_id),
+            ) else {
+                continue;
+            };
+            for packet in packets {
+                path.outgoing.send(packet);
+            }
+            link.to_client.extend(path.outgoing.receive());
+        }
+    }
+}
+
+/// Client end of a `LoopbackHub`, used in place of `NetcodeClientTransport`.
+#[derive(Debug)]
+pub struct LoopbackClientTransport {
+    hub: LoopbackHub,
+    client_id: u64,
+    path: Conditioners,
+}
+
+impl LoopbackClientTransport {
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        self.path.incoming.conditions()
+    }
+
+    /// Impairs traffic both ways between this client and the server.
+    pub fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.path.set_conditions(conditions);
+    }
+
+    /// Hands the client every packet from the server that is due by now, or
+    /// disconnects it if the server hung up.
+    pub fn update(&mut self, dt: Duration, client: &mut RenetClient) {
+        let mut links = self.hub.lock();
+        let Some(link) = links.open.get_mut(&self.client_id) else {
+            client.disconnect_due_to_transport();
+            return;
+        };
+        if link.closed {
+            links.open.remove(&self.client_id);
+            client.disconnect_due_to_transport();
+            return;
+        }
+        if !client.is_connected() && !client.is_disconnected() {
+            client.set_connected();
+        }
+        self.path.advance(dt);
+        for packet in link.to_client.drain(..) {
+            self.path.incoming.send(packet);
+        }
+        for packet in self.path.incoming.receive() {
+            client.process_packet(&packet);
+        }
+    }
+
+    pub fn send_packets(&mut self, client: &mut RenetClient) {
+        if client.is_disconnected() {
+            self.disconnect();
+            return;
+        }
+        for packet in client.get_packets_to_send() {
+            self.path.outgoing.send(packet);
+        }
+        let packets = self.path.outgoing.receive();
+        if let Some(link) = self.hub.lock().open.get_mut(&self.client_id) {
+            link.to_server.extend(packets);
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some(link) = self.hub.lock().open.get_mut(&self.client_id) {
+            link.closed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connection_config, Channel};
+
+    fn pump(
+        server: &mut RenetServer,
+        server_transport: &mut LoopbackServerTransport,
+        client: &mut RenetClient,
+        client_transport: &mut LoopbackClientTransport,
+    ) {
+        client_transport.send_packets(client);
+        server_transport.update(Duration::ZERO, server);
+        server_transport.send_packets(server);
+        client_transport.update(Duration::ZERO, client);
+    }
+
+    #[test]
+    fn messages_cross_in_both_directions() {
+        let hub = LoopbackHub::new();
+        let mut server = RenetServer::new(connection_config());
+        let mut server_transport = hub.server();
+        let mut client = RenetClient::new(connection_config());
+        let mut client_transport = hub.connect();
+        let client_id = client_transport.client_id();
+
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut client,
+            &mut client_transport,
+        );
+        assert!(client.is_connected());
+        assert_eq!(server.clients_id(), vec![client_id]);
+
+        client.send_message(Channel::Reliable, b"hello".to_vec());
+        server.send_message(client_id, Channel::Unreliable, b"world".to_vec());
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut client,
+            &mut client_transport,
+        );
+        assert_eq!(
+            server
+                .receive_message(client_id, Channel::Reliable)
+                .as_deref(),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(
+            client.receive_message(Channel::Unreliable).as_deref(),
+            Some(&b"world"[..])
+        );
+    }
+
+    #[test]
+    fn client_conditions_delay_its_packets() {
+        let hub = LoopbackHub::new();
+        let mut server = RenetServer::new(connection_config());
+        let mut server_transport = hub.server();
+        let mut client = RenetClient::new(connection_config());
+        let mut client_transport = hub.connect();
+        let client_id = client_transport.client_id();
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut client,
+            &mut client_transport,
+        );
+
+        client_transport.set_conditions(NetworkConditions {
+            latency_ms: 50,
+            ..Default::default()
+        });
+        client.send_message(Channel::Reliable, b"late".to_vec());
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut client,
+            &mut client_transport,
+        );
+        assert!(server
+            .receive_message(client_id, Channel::Reliable)
+            .is_none());
+
+        client_transport.update(Duration::from_millis(50), &mut client);
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut client,
+            &mut client_transport,
+        );
+        assert!(server
+            .receive_message(client_id, Channel::Reliable)
+            .is_some());
+    }
+
+    #[test]
+    fn hanging_up_reaches_the_other_side() {
+        let hub = LoopbackHub::new();
+        let mut server = RenetServer::new(connection_config());
+        let mut server_transport = hub.server();
+        let mut first = RenetClient::new(connection_config());
+        let mut first_transport = hub.connect();
+        let mut second = RenetClient::new(connection_config());
+        let mut second_transport = hub.connect();
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut first,
+            &mut first_transport,
+        );
+        pump(
+            &mut server,
+            &mut server_transport,
+            &mut second,
+            &mut second_transport,
+        );
+        assert_eq!(server.clients_id().len(), 2);
+
+        // Client hangs up
+        first_transport.disconnect();
+        server_transport.update(Duration::ZERO, &mut server);
+        assert_eq!(server.clients_id(), vec![second_transport.client_id()]);
+
+        // Server kicks
+        server.disconnect(second_transport.client_id());
+        server_transport.update(Duration::ZERO, &mut server);
+        second_transport.update(Duration::ZERO, &mut second);
+        assert!(second.is_disconnected());
+        assert!(server.clients_id().is_empty());
+    }
+}
@@ -1,4 +1,34 @@
+use glam::Vec3;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{PlayerKinematics, RoomSettings, MAX_NAME_CHARS};
+
+/// Positions travel in steps of 1/8 world unit, saturating at ±4096 units,
+/// which covers every `Track::extent`.
+pub const POSITION_SCALE: f32 = 8.0;
+/// Velocities travel in steps of 1/16 unit/s, saturating at ±2048 units/s.
+pub const VELOCITY_SCALE: f32 = 16.0;
+/// Stamina travels in steps of 0.5, covering 0..=127.5.
+pub const STAMINA_SCALE: f32 = 2.0;
+
+/// Fixed-point encoding of a vector with `scale` steps per unit. Values out
+/// of range saturate rather than wrap.
+pub fn quantize_vec3(v: Vec3, scale: f32) -> [i16; 3] {
+    (v * scale).round().to_array().map(|c| c as i16)
+}
+
+pub fn dequantize_vec3(q: [i16; 3], scale: f32) -> Vec3 {
+    Vec3::from(q.map(f32::from)) / scale
+}
+
+pub fn quantize_stamina(stamina: f32) -> u8 {
+    (stamina * STAMINA_SCALE).round() as u8
+}
+
+pub fn dequantize_stamina(q: u8) -> f32 {
+    f32::from(q) / STAMINA_SCALE
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerSummary {
@@ -9,14 +39,65 @@ pub struct PlayerSummary {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpectatorSummary {
+    pub id: u64,
+    pub name: String,
+}
+
+/// Quantized entity state; see [`POSITION_SCALE`], [`VELOCITY_SCALE`] and
+/// [`STAMINA_SCALE`] for the precision of each field.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct EntitySnapshot {
     pub id: u64,
-    pub position: [f32; 3],
-    pub velocity: [f32; 3],
-    pub stamina: f32,
+    pub position: [i16; 3],
+    pub velocity: [i16; 3],
+    pub stamina: u8,
     pub region: RegionId,
 }
 
+impl EntitySnapshot {
+    pub fn new(id: u64, position: Vec3, velocity: Vec3, stamina: f32, region: RegionId) -> Self {
+        Self {
+            id,
+            position: quantize_vec3(position, POSITION_SCALE),
+            velocity: quantize_vec3(velocity, VELOCITY_SCALE),
+            stamina: quantize_stamina(stamina),
+            region,
+        }
+    }
+
+    pub fn position(&self) -> Vec3 {
+        dequantize_vec3(self.position, POSITION_SCALE)
+    }
+
+    pub fn velocity(&self) -> Vec3 {
+        dequantize_vec3(self.velocity, VELOCITY_SCALE)
+    }
+
+    pub fn stamina(&self) -> f32 {
+        dequantize_stamina(self.stamina)
+    }
+
+    pub fn kinematics(&self) -> PlayerKinematics {
+        PlayerKinematics {
+            position: self.position(),
+            velocity: self.velocity(),
+            stamina: self.stamina(),
+        }
+    }
+}
+
+/// Fields of an entity that changed since the client's acknowledged baseline.
+/// An entity missing from the baseline carries every field.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct EntityDelta {
+    pub id: u64,
+    pub position: Option<[i16; 3]>,
+    pub velocity: Option<[i16; 3]>,
+    pub stamina: Option<u8>,
+    pub region: Option<RegionId>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeaderboardEntry {
     pub name: String,
@@ -25,34 +106,150 @@ pub struct LeaderboardEntry {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
+    /// Must stay the first variant with this exact shape so that any build
+    /// can decode it and report a version mismatch.
+    Hello {
+        protocol_version: u32,
+        build: String,
+    },
     JoinRoom {
         name: String,
         room_code: Option<String>,
+        /// Watch without racing. Joining a race already under way always
+        /// spectates.
+        spectate: bool,
     },
     SetReady {
         ready: bool,
     },
-    InputFrame(InputFrame),
+    /// The newest input frames not yet acknowledged, oldest first, so a
+    /// lost packet doesn't lose the frames it carried.
+    Inputs(Vec<InputFrame>),
     StartRace,
+    /// Newest snapshot tick the client has reconstructed.
+    SnapshotAck {
+        tick: u32,
+    },
+    /// Clock sync request; `client_time` is local seconds, echoed back.
+    Ping {
+        client_time: f64,
+    },
+    /// Reclaims a slot held since a dropped connection, instead of `JoinRoom`.
+    Resume {
+        session_token: u64,
+    },
+    /// Hands the host role to another racer in the room; host only.
+    TransferHost {
+        to: u64,
+    },
+    /// Removes a racer or spectator from the room, and with `ban` keeps
+    /// them out for as long as the room exists; host only.
+    Kick {
+        id: u64,
+        ban: bool,
+    },
+    /// Closes the room to new racers and spectators, or opens it again;
+    /// host only. Held slots can still be resumed.
+    LockRoom {
+        locked: bool,
+    },
+    /// Replaces the room's race settings; host only, in the lobby.
+    SetRoomSettings {
+        settings: Box<RoomSettings>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
+    /// Like `ClientMessage::Hello`, these two variants must keep their
+    /// position and shape across protocol versions.
+    Hello {
+        protocol_version: u32,
+        build: String,
+    },
+    JoinRejected {
+        reason: JoinRejectReason,
+    },
     RoomState {
         room_code: String,
         players: Vec<PlayerSummary>,
+        spectators: Vec<SpectatorSummary>,
         state: RoomPhase,
+        locked: bool,
+        /// Names of everyone banned from the room.
+        banned: Vec<String>,
+        settings: Box<RoomSettings>,
     },
     Countdown {
         millis_left: u32,
     },
     Snapshot {
         tick: u32,
+        /// Highest input tick of the receiving client simulated so far.
+        last_input: Option<u32>,
+        /// Room race tick the next input simulated will run on.
+        race_tick: u32,
         entities: Vec<EntitySnapshot>,
     },
+    /// Snapshot encoded against the client-acknowledged `baseline` tick.
+    SnapshotDelta {
+        tick: u32,
+        last_input: Option<u32>,
+        race_tick: u32,
+        baseline: u32,
+        changed: Vec<EntityDelta>,
+        removed: Vec<u64>,
+    },
     RaceFinished {
         leaderboard: Vec<LeaderboardEntry>,
     },
+    /// Reply to `ClientMessage::Ping` with the fractional server tick it
+    /// was sent at.
+    Pong {
+        client_time: f64,
+        server_tick: f64,
+    },
+    /// Sent after a successful `JoinRoom` or `Resume`. The token lets a
+    /// racer resume their slot if the connection drops; spectators have
+    /// no slot and get none.
+    Joined {
+        room_code: String,
+        session_token: Option<u64>,
+        /// Server ticks per second, which the client simulates at too.
+        tick_rate: u32,
+    },
+    /// The host removed us from the room; the server hangs up shortly after.
+    Kicked {
+        reason: KickReason,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Error)]
+pub enum JoinRejectReason {
+    #[error("version mismatch: server speaks protocol {server}, this client {client}")]
+    VersionMismatch { server: u32, client: u32 },
+    #[error("room is full")]
+    RoomFull,
+    #[error("no room with that code")]
+    BadRoomCode,
+    #[error("server cannot host any more rooms")]
+    ServerFull,
+    #[error("session expired")]
+    SessionExpired,
+    #[error("name must be 1 to {MAX_NAME_CHARS} letters, digits, spaces, '-', '_' or '.'")]
+    InvalidName,
+    #[error("room is locked")]
+    RoomLocked,
+    #[error("banned from that room")]
+    Banned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Error)]
+pub enum KickReason {
+    #[error("kicked by the host")]
+    Kicked,
+    #[error("banned by the host")]
+    Banned,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -62,6 +259,9 @@ pub struct InputFrame {
     pub down: bool,
     pub left: bool,
     pub right: bool,
+    /// Swim up or down through the tube, along y.
+    pub ascend: bool,
+    pub descend: bool,
     pub boost: bool,
 }
 
@@ -82,3 +282,84 @@ pub enum RegionId {
     Tube,
     Ampulla,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Track, BOOST_SPEED, BUILD, PROTOCOL_VERSION, TRACK_LENGTH};
+
+    #[test]
+    fn position_round_trip_within_precision() {
+        let samples = [
+            Vec3::new(-100.0, 0.0, 0.0),
+            Vec3::new(1234.567, -89.9, 45.01),
+            Vec3::new(TRACK_LENGTH, 160.0, -160.0),
+        ];
+        for pos in samples {
+            let back = dequantize_vec3(quantize_vec3(pos, POSITION_SCALE), POSITION_SCALE);
+            assert!((back - pos).abs().max_element() <= 0.5 / POSITION_SCALE);
+        }
+    }
+
+    #[test]
+    fn track_ends_round_trip() {
+        for track in Track::ALL {
+            let (behind, past) = track.extent();
+            for x in [behind, past] {
+                let pos = Vec3::new(x, 0.0, 0.0);
+                let back = dequantize_vec3(quantize_vec3(pos, POSITION_SCALE), POSITION_SCALE);
+                assert_eq!(back, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn velocity_and_stamina_round_trip_within_precision() {
+        let vel = Vec3::new(BOOST_SPEED, -BOOST_SPEED * 0.6, 0.3);
+        let back = dequantize_vec3(quantize_vec3(vel, VELOCITY_SCALE), VELOCITY_SCALE);
+        assert!((back - vel).abs().max_element() <= 0.5 / VELOCITY_SCALE);
+
+        for stamina in [0.0, 33.3, 99.9, 100.0] {
+            let back = dequantize_stamina(quantize_stamina(stamina));
+            assert!((back - stamina).abs() <= 0.5 / STAMINA_SCALE);
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_saturate() {
+        let far = Vec3::new(1.0e6, -1.0e6, 0.0);
+        assert_eq!(quantize_vec3(far, POSITION_SCALE), [i16::MAX, i16::MIN, 0]);
+        assert_eq!(quantize_stamina(500.0), u8::MAX);
+        assert_eq!(quantize_stamina(-5.0), 0);
+    }
+
+    #[test]
+    fn handshake_variants_keep_their_wire_tags() {
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            build: BUILD.into(),
+        };
+        let rejected = ServerMessage::JoinRejected {
+            reason: JoinRejectReason::RoomFull,
+        };
+        assert_eq!(bincode::serialize(&hello).unwrap()[..4], [0, 0, 0, 0]);
+        assert_eq!(bincode::serialize(&rejected).unwrap()[..4], [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn quantized_snapshot_is_smaller() {
+        let snapshot = EntitySnapshot::new(
+            7,
+            Vec3::new(812.3, 12.0, -40.5),
+            Vec3::new(BOOST_SPEED, 0.0, 0.0),
+            72.5,
+            RegionId::Cervix,
+        );
+        let quantized = bincode::serialize(&snapshot).unwrap().len();
+        let unquantized = bincode::serialize(&(7u64, [0f32; 3], [0f32; 3], 0f32, RegionId::Cervix))
+            .unwrap()
+            .len();
+        assert!(quantized < unquantized);
+        assert_eq!(snapshot.stamina(), 72.5);
+    }
+}
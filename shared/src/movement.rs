@@ -1,10 +1,10 @@
-use glam::{vec3, Vec3};
+use glam::{Quat, Vec3};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 
+use crate::{region_for_position, tube_radius, InputFrame, RoomSettings, PLAYER_RADIUS};
 #[cfg(test)]
-use crate::TICK_RATE;
-use crate::{BASE_SPEED, BOOST_COST, BOOST_REGEN, BOOST_SPEED, PLAYER_RADIUS};
+use crate::{start_grid_position, BASE_SPEED, BOOST_SPEED, TICK_RATE};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PlayerKinematics {
@@ -13,6 +13,44 @@ pub struct PlayerKinematics {
     pub stamina: f32,
 }
 
+/// How a swimmer responds to thrust, per room. Thrust is whatever holds
+/// the room's base or boost speed against drag, so those stay the speeds
+/// a held key settles at.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handling {
+    /// Drag per second per unit of speed.
+    pub linear_drag: f32,
+    /// Drag per second per unit of speed squared.
+    pub quadratic_drag: f32,
+    /// Hard cap on speed, whatever the thrust.
+    pub max_speed: f32,
+    /// How fast the thrust can swing round, in radians per second.
+    pub turn_rate: f32,
+}
+
+impl Default for Handling {
+    fn default() -> Self {
+        Self {
+            linear_drag: 2.0,
+            quadratic_drag: 0.004,
+            max_speed: 600.0,
+            turn_rate: 4.0,
+        }
+    }
+}
+
+impl Handling {
+    /// Acceleration that balances drag at `speed`.
+    pub fn thrust_for(&self, speed: f32) -> f32 {
+        self.linear_drag * speed + self.quadratic_drag * speed * speed
+    }
+
+    /// Deceleration from drag at `velocity`.
+    pub fn drag(&self, velocity: Vec3) -> Vec3 {
+        velocity * (self.linear_drag + self.quadratic_drag * velocity.length())
+    }
+}
+
 impl PlayerKinematics {
     pub fn spawn(start: Vec3) -> Self {
         Self {
@@ -25,8 +63,9 @@ impl PlayerKinematics {
 
 pub fn integrate_input(
     mut kin: PlayerKinematics,
-    input: &crate::InputFrame,
+    input: &InputFrame,
     dt: f32,
+    settings: &RoomSettings,
 ) -> PlayerKinematics {
     let mut dir = Vec3::ZERO;
     if input.up {
@@ -41,27 +80,106 @@ pub fn integrate_input(
     if input.right {
         dir.z += 1.0;
     }
+    if input.ascend {
+        dir.y += 1.0;
+    }
+    if input.descend {
+        dir.y -= 1.0;
+    }
 
-    let speed = if input.boost && kin.stamina > 0.1 {
-        kin.stamina = (kin.stamina - BOOST_COST * dt).max(0.0);
-        BOOST_SPEED
+    let target_speed = if input.boost && kin.stamina > 0.1 {
+        kin.stamina = (kin.stamina - settings.boost_cost * dt).max(0.0);
+        settings.boost_speed
     } else {
-        kin.stamina = (kin.stamina + BOOST_REGEN * dt).min(100.0);
-        BASE_SPEED
+        kin.stamina = (kin.stamina + settings.boost_regen * dt).min(100.0);
+        settings.base_speed
     };
 
-    let accel = if dir.length_squared() > 0.01 {
-        dir.normalize() * speed
+    let handling = &settings.handling;
+    let thrust = if dir.length_squared() > 0.01 {
+        let heading = steer(kin.velocity, dir.normalize(), handling.turn_rate * dt);
+        heading * handling.thrust_for(target_speed)
     } else {
-        vec3(0.0, 0.0, 0.0)
+        Vec3::ZERO
     };
 
-    kin.velocity = accel;
+    let accel = thrust - handling.drag(kin.velocity);
+    kin.velocity = (kin.velocity + accel * dt).clamp_length_max(handling.max_speed);
     kin.position =
         integrate_3d_position(kin.position.to_array(), kin.velocity.to_array(), dt).into();
     kin
 }
 
+/// Direction to thrust in: `desired`, unless that means swinging the
+/// current heading round by more than `max_turn` radians. Thrusting
+/// against the heading brakes straight away.
+fn steer(velocity: Vec3, desired: Vec3, max_turn: f32) -> Vec3 {
+    let Some(heading) = velocity.try_normalize() else {
+        return desired;
+    };
+    let angle = heading.angle_between(desired);
+    if angle <= max_turn || heading.dot(desired) < 0.0 {
+        return desired;
+    }
+    let Some(axis) = heading.cross(desired).try_normalize() else {
+        return desired;
+    };
+    Quat::from_axis_angle(axis, max_turn) * heading
+}
+
+/// One tick of player movement, `race_tick` ticks into the room's race:
+/// integrate the input, let the region act on the player, then keep them
+/// inside its tube and on the track. The server simulation and client prediction both go
+/// through here, with the room's settings, so they agree exactly.
+pub fn simulate_tick(
+    kin: PlayerKinematics,
+    input: &InputFrame,
+    dt: f32,
+    settings: &RoomSettings,
+    race_tick: u32,
+) -> PlayerKinematics {
+    let kin = integrate_input(kin, input, dt, settings);
+    // After integrating, so that regen doesn't make up for damage
+    let region = region_for_position(kin.position, settings.track);
+    let mut kin = settings
+        .environment
+        .region(region)
+        .apply(kin, race_tick, dt);
+    let radius = tube_radius(region);
+    let clamped = clamp_to_radius(kin.position, radius);
+    if clamped != kin.position {
+        // The wall soaks up the swim into it
+        let normal = Vec3::new(0.0, clamped.y, clamped.z).normalize_or_zero();
+        kin.velocity -= normal * kin.velocity.dot(normal).max(0.0);
+    }
+    kin.position = clamped;
+    // Nor can anyone swim off either end of the track
+    let (behind, past) = settings.track.extent();
+    let x = kin.position.x.clamp(behind, past);
+    if x != kin.position.x {
+        kin.position.x = x;
+        kin.velocity.x = 0.0;
+    }
+    kin
+}
+
+/// Re-simulates `inputs` on top of an authoritative state, the first on
+/// `race_tick` and each one after on the next.
+pub fn replay_inputs<'a>(
+    kin: PlayerKinematics,
+    inputs: impl IntoIterator<Item = &'a InputFrame>,
+    dt: f32,
+    settings: &RoomSettings,
+    race_tick: u32,
+) -> PlayerKinematics {
+    inputs
+        .into_iter()
+        .zip(race_tick..)
+        .fold(kin, |kin, (input, race_tick)| {
+            simulate_tick(kin, input, dt, settings, race_tick)
+        })
+}
+
 pub fn integrate_3d_position(pos: [f32; 3], vel: [f32; 3], dt: f32) -> [f32; 3] {
     let position = Vec3::from(pos) + Vec3::from(vel) * dt;
     position.to_array()
@@ -95,6 +213,44 @@ pub fn overlaps(a: Vec3, b: Vec3) -> bool {
     distance(a, b) < PLAYER_RADIUS * 2.0
 }
 
+/// Pushes overlapping players apart and swaps their speeds along the line
+/// between them, as equal balls bouncing off each other would. Pairs are
+/// taken in slice order, so keep the order stable for repeatable results.
+pub fn resolve_collisions(bodies: &mut [PlayerKinematics], settings: &RoomSettings) {
+    for i in 0..bodies.len() {
+        let (head, tail) = bodies.split_at_mut(i + 1);
+        for other in tail {
+            collide(&mut head[i], other, settings);
+        }
+    }
+}
+
+fn collide(a: &mut PlayerKinematics, b: &mut PlayerKinematics, settings: &RoomSettings) {
+    if !overlaps(a.position, b.position) {
+        return;
+    }
+    let offset = b.position - a.position;
+    // Right on top of each other, part them along the track
+    let normal = offset.try_normalize().unwrap_or(Vec3::X);
+    let push = normal * (PLAYER_RADIUS * 2.0 - offset.length()) / 2.0;
+    for (kin, push) in [(&mut *a, -push), (&mut *b, push)] {
+        let radius = tube_radius(region_for_position(kin.position + push, settings.track));
+        kin.position = clamp_to_radius(kin.position + push, radius);
+    }
+
+    let a_in = a.velocity.dot(normal);
+    let b_in = b.velocity.dot(normal);
+    if a_in <= b_in {
+        // Already drifting apart
+        return;
+    }
+    a.velocity += normal * (b_in - a_in);
+    b.velocity += normal * (a_in - b_in);
+    // Whoever swam harder into the other pays for the bump
+    let rammer = if a_in >= -b_in { a } else { b };
+    rammer.stamina = (rammer.stamina - settings.bump_cost).max(0.0);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,11 +258,16 @@ mod tests {
     #[test]
     fn integrates_upward_motion() {
         let kin = PlayerKinematics::spawn(Vec3::ZERO);
-        let input = crate::InputFrame {
+        let input = InputFrame {
             up: true,
             ..Default::default()
         };
-        let result = integrate_input(kin, &input, 1.0 / TICK_RATE as f32);
+        let result = integrate_input(
+            kin,
+            &input,
+            1.0 / TICK_RATE as f32,
+            &RoomSettings::default(),
+        );
         assert!(result.position.x > 0.0);
     }
 
@@ -116,4 +277,234 @@ mod tests {
         let clamped = clamp_to_radius(pos, 100.0);
         assert!((clamped.length() - 100.0).abs() < 0.01);
     }
+
+    #[test]
+    fn replay_matches_tick_by_tick_simulation() {
+        let dt = 1.0 / TICK_RATE as f32;
+        let settings = RoomSettings::default();
+        let inputs: Vec<InputFrame> = (0..30)
+            .map(|tick| InputFrame {
+                tick,
+                up: true,
+                right: tick % 3 == 0,
+                boost: tick < 10,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut stepped = PlayerKinematics::spawn(Vec3::ZERO);
+        for input in &inputs {
+            stepped = simulate_tick(stepped, input, dt, &settings, input.tick);
+        }
+        let start = PlayerKinematics::spawn(Vec3::ZERO);
+        let replayed = replay_inputs(start, &inputs, dt, &settings, 0);
+
+        assert_eq!(replayed.position, stepped.position);
+        assert_eq!(replayed.stamina, stepped.stamina);
+    }
+
+    #[test]
+    fn simulate_tick_keeps_player_in_tube() {
+        let mut kin = PlayerKinematics::spawn(Vec3::new(500.0, 0.0, 0.0));
+        let input = InputFrame {
+            right: true,
+            ..Default::default()
+        };
+        let settings = RoomSettings::default();
+        for _ in 0..TICK_RATE * 2 {
+            kin = simulate_tick(kin, &input, 1.0 / TICK_RATE as f32, &settings, 0);
+        }
+        let radius = tube_radius(region_for_position(kin.position, settings.track));
+        assert!((kin.position.z - radius).abs() < 0.01);
+    }
+
+    #[test]
+    fn simulate_tick_keeps_player_on_the_track() {
+        let settings = RoomSettings::default();
+        let dt = 1.0 / TICK_RATE as f32;
+        let (behind, past) = settings.track.extent();
+        assert!(start_grid_position(63).x > behind);
+
+        for (input, end) in [
+            (
+                InputFrame {
+                    down: true,
+                    ..Default::default()
+                },
+                behind,
+            ),
+            (
+                InputFrame {
+                    up: true,
+                    ..Default::default()
+                },
+                past,
+            ),
+        ] {
+            let mut kin = PlayerKinematics::spawn(start_grid_position(0));
+            for _ in 0..TICK_RATE * 30 {
+                kin = simulate_tick(kin, &input, dt, &settings, 0);
+            }
+            assert_eq!(kin.position.x, end);
+        }
+    }
+
+    #[test]
+    fn rises_and_dives_through_the_tube() {
+        let settings = RoomSettings::default();
+        let dt = 1.0 / TICK_RATE as f32;
+        let rise = InputFrame {
+            ascend: true,
+            ..Default::default()
+        };
+        let mut kin = PlayerKinematics::spawn(Vec3::new(500.0, 0.0, 0.0));
+        for _ in 0..TICK_RATE * 2 {
+            kin = simulate_tick(kin, &rise, dt, &settings, 0);
+        }
+        let radius = tube_radius(region_for_position(kin.position, settings.track));
+        assert!((kin.position.y - radius).abs() < 0.01);
+        assert_eq!(kin.velocity.y, 0.0);
+
+        let dive = InputFrame {
+            descend: true,
+            ..Default::default()
+        };
+        let dived = simulate_tick(kin.clone(), &dive, dt, &settings, 0);
+        assert!(dived.position.y < kin.position.y);
+    }
+
+    #[test]
+    fn regions_act_on_every_tick() {
+        let settings = RoomSettings::default();
+        let dt = 1.0 / TICK_RATE as f32;
+        let swim = InputFrame {
+            up: true,
+            ..Default::default()
+        };
+        let cruise = |x: f32| {
+            let mut kin = PlayerKinematics {
+                velocity: Vec3::new(BASE_SPEED, 0.0, 0.0),
+                ..PlayerKinematics::spawn(Vec3::new(x, 0.0, 0.0))
+            };
+            for race_tick in 0..10 {
+                kin = simulate_tick(kin, &swim, dt, &settings, race_tick);
+            }
+            kin.velocity.x
+        };
+        // Mucus in the cervix, flow in the tube
+        assert!(cruise(500.0) < BASE_SPEED);
+        assert!(cruise(3000.0) > BASE_SPEED);
+    }
+
+    /// Velocity after holding `input` from rest for `seconds`.
+    fn hold(input: &InputFrame, seconds: u32, settings: &RoomSettings) -> Vec3 {
+        let dt = 1.0 / TICK_RATE as f32;
+        let mut kin = PlayerKinematics::spawn(Vec3::ZERO);
+        for _ in 0..TICK_RATE * seconds {
+            kin = integrate_input(kin, input, dt, settings);
+        }
+        kin.velocity
+    }
+
+    #[test]
+    fn terminal_speeds_match_base_and_boost_speed() {
+        let settings = RoomSettings {
+            boost_cost: 0.0,
+            ..Default::default()
+        };
+        let swim = InputFrame {
+            up: true,
+            ..Default::default()
+        };
+        let boost = InputFrame {
+            boost: true,
+            ..swim.clone()
+        };
+        assert!((hold(&swim, 5, &settings).x - BASE_SPEED).abs() < 0.5);
+        assert!((hold(&boost, 5, &settings).x - BOOST_SPEED).abs() < 0.5);
+
+        let diagonal = InputFrame {
+            right: true,
+            ..swim
+        };
+        assert!((hold(&diagonal, 5, &settings).length() - BASE_SPEED).abs() < 0.5);
+    }
+
+    #[test]
+    fn speed_builds_up_and_carries_on() {
+        let settings = RoomSettings::default();
+        let dt = 1.0 / TICK_RATE as f32;
+        let swim = InputFrame {
+            up: true,
+            ..Default::default()
+        };
+        let first = integrate_input(PlayerKinematics::spawn(Vec3::ZERO), &swim, dt, &settings);
+        assert!(first.velocity.x > 0.0 && first.velocity.x < BASE_SPEED / 2.0);
+
+        let cruising = PlayerKinematics {
+            velocity: Vec3::new(BASE_SPEED, 0.0, 0.0),
+            ..PlayerKinematics::spawn(Vec3::ZERO)
+        };
+        let coasting = integrate_input(cruising, &InputFrame::default(), dt, &settings);
+        assert!(coasting.velocity.x > BASE_SPEED * 0.9 && coasting.velocity.x < BASE_SPEED);
+    }
+
+    #[test]
+    fn heading_swings_round_at_the_turn_rate() {
+        let settings = RoomSettings::default();
+        let dt = 1.0 / TICK_RATE as f32;
+        let cruising = PlayerKinematics {
+            velocity: Vec3::new(BASE_SPEED, 0.0, 0.0),
+            ..PlayerKinematics::spawn(Vec3::ZERO)
+        };
+        let right = InputFrame {
+            right: true,
+            ..Default::default()
+        };
+        let turned = integrate_input(cruising.clone(), &right, dt, &settings);
+        assert!(turned.velocity.z > 0.0);
+        assert!(turned.velocity.x > BASE_SPEED * 0.9);
+
+        // Pulling back brakes harder than letting go, without swerving
+        let back = InputFrame {
+            down: true,
+            ..Default::default()
+        };
+        let coasting = integrate_input(cruising.clone(), &InputFrame::default(), dt, &settings);
+        let braked = integrate_input(cruising, &back, dt, &settings);
+        assert!(braked.velocity.x < coasting.velocity.x);
+        assert_eq!(braked.velocity.z, 0.0);
+    }
+
+    #[test]
+    fn bumping_parts_players_and_trades_their_speed() {
+        let settings = RoomSettings::default();
+        let rammer = PlayerKinematics {
+            position: Vec3::new(500.0, 0.0, 0.0),
+            velocity: Vec3::new(BOOST_SPEED, 0.0, 0.0),
+            stamina: 50.0,
+        };
+        let cruiser = PlayerKinematics {
+            position: Vec3::new(500.0 + PLAYER_RADIUS, 0.0, 0.0),
+            velocity: Vec3::new(BASE_SPEED, 0.0, 0.0),
+            stamina: 50.0,
+        };
+        let mut bodies = [rammer, cruiser];
+        resolve_collisions(&mut bodies, &settings);
+        let [rammer, cruiser] = bodies;
+
+        assert!(!overlaps(rammer.position, cruiser.position));
+        assert_eq!(rammer.velocity.x, BASE_SPEED);
+        assert_eq!(cruiser.velocity.x, BOOST_SPEED);
+        assert_eq!(rammer.stamina, 50.0 - settings.bump_cost);
+        assert_eq!(cruiser.stamina, 50.0);
+    }
+
+    #[test]
+    fn nobody_starts_on_top_of_anyone() {
+        let grid: Vec<Vec3> = (0..64).map(start_grid_position).collect();
+        for (i, a) in grid.iter().enumerate() {
+            assert!(grid[i + 1..].iter().all(|b| !overlaps(*a, *b)));
+        }
+    }
 }
@@ -1,16 +1,19 @@
 use glam::Vec3;
 use serde::{Deserialize, Serialize};
 
-use crate::{RegionId, REGION_MARKERS, REGION_NAMES};
+#[cfg(test)]
+use crate::REGION_MARKERS;
+use crate::{RegionId, Track, REGION_NAMES};
 
-pub fn region_for_position(position: Vec3) -> RegionId {
+pub fn region_for_position(position: Vec3, track: Track) -> RegionId {
     let x = position.x.max(0.0);
+    let markers = track.markers();
     match x {
-        v if v < REGION_MARKERS[1] => RegionId::Vagina,
-        v if v < REGION_MARKERS[2] => RegionId::Cervix,
-        v if v < REGION_MARKERS[3] => RegionId::Uterus,
-        v if v < REGION_MARKERS[4] => RegionId::Utj,
-        v if v < REGION_MARKERS[5] => RegionId::Tube,
+        v if v < markers[1] => RegionId::Vagina,
+        v if v < markers[2] => RegionId::Cervix,
+        v if v < markers[3] => RegionId::Uterus,
+        v if v < markers[4] => RegionId::Utj,
+        v if v < markers[5] => RegionId::Tube,
         _ => RegionId::Ampulla,
     }
 }
@@ -93,7 +96,7 @@ mod tests {
         ];
 
         for (pos, expected) in checkpoints {
-            assert_eq!(region_for_position(pos), expected);
+            assert_eq!(region_for_position(pos, Track::Full), expected);
         }
     }
 }
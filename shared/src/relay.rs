@@ -0,0 +1,197 @@
+//! A UDP relay that impairs the traffic passing through it, so a real
+//! netcode server and its clients can be tried under latency and loss.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{loopback::Conditioners, NetworkConditions};
+
+/// Largest datagram relayed; netcode packets are well under it.
+const MAX_DATAGRAM: usize = 1500;
+/// Peers silent for this long are forgotten.
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Pause between two passes over the sockets.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Forwards datagrams from peers on its own address to `target`, and the
+/// replies back, impairing both ways by `NetworkConditions`. Each peer
+/// gets a socket of its own towards the target, so the target still tells
+/// them apart. Runs on a background thread until dropped.
+#[derive(Debug)]
+pub struct UdpRelay {
+    local_addr: SocketAddr,
+    conditions: Arc<Mutex<NetworkConditions>>,
+}
+
+/// One peer: its socket towards the target and both directions of its path.
+struct Peer {
+    upstream: UdpSocket,
+    path: Conditioners,
+    last_heard: Instant,
+}
+
+impl UdpRelay {
+    /// Listens on `listen` and relays to `target`.
+    pub fn spawn(
+        listen: SocketAddr,
+        target: SocketAddr,
+        conditions: NetworkConditions,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(listen)?;
+        socket.set_nonblocking(true)?;
+        let relay = Self {
+            local_addr: socket.local_addr()?,
+            conditions: Arc::new(Mutex::new(conditions)),
+        };
+        let shared = relay.conditions.clone();
+        thread::spawn(move || run(socket, target, shared));
+        Ok(relay)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        *self.conditions.lock().unwrap()
+    }
+
+    /// Applies to packets relayed from now on.
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        *self.conditions.lock().unwrap() = conditions;
+    }
+}
+
+fn run(socket: UdpSocket, target: SocketAddr, conditions: Arc<Mutex<NetworkConditions>>) {
+    let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+    let mut buf = [0u8; MAX_DATAGRAM];
+    let mut last_pass = Instant::now();
+    let mut seed = 0;
+    // The handle holds the only other reference
+    while Arc::strong_count(&conditions) > 1 {
+        let now = Instant::now();
+        let dt = now - last_pass;
+        last_pass = now;
+        let current = *conditions.lock().unwrap();
+
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            let peer = match peers.entry(from) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let Ok(upstream) = connect_upstream(target) else {
+                        continue;
+                    };
+                    seed += 1;
+                    entry.insert(Peer {
+                        upstream,
+                        path: Conditioners::new(current, seed),
+                        last_heard: now,
+                    })
+                }
+            };
+            peer.last_heard = now;
+            peer.path.outgoing.send(buf[..len].to_vec());
+        }
+
+        for (addr, peer) in &mut peers {
+            while let Ok(len) = peer.upstream.recv(&mut buf) {
+                peer.path.incoming.send(buf[..len].to_vec());
+            }
+            peer.path.set_conditions(current);
+            peer.path.advance(dt);
+            // Lost sends are just more packet loss
+            for packet in peer.path.outgoing.receive() {
+                let _ = peer.upstream.send(&packet);
+            }
+            for packet in peer.path.incoming.receive() {
+                let _ = socket.send_to(&packet, *addr);
+            }
+        }
+        peers.retain(|_, peer| now - peer.last_heard < PEER_TIMEOUT);
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn connect_upstream(target: SocketAddr) -> io::Result<UdpSocket> {
+    let any: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let upstream = UdpSocket::bind(any)?;
+    upstream.connect(target)?;
+    upstream.set_nonblocking(true)?;
+    Ok(upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+
+    /// Whether `err` only means there is nothing to read yet.
+    fn would_block(err: &io::Error) -> bool {
+        matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+    }
+
+    /// A socket echoing whatever it gets, on a thread of its own.
+    fn echo_server() -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_DATAGRAM];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(&buf[..len], from);
+            }
+        });
+        addr
+    }
+
+    /// Sends `count` packets through `relay` and collects the echoes.
+    fn round_trip(relay: &UdpRelay, count: u8) -> Vec<Vec<u8>> {
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        for i in 0..count {
+            client.send_to(&[i], relay.local_addr()).unwrap();
+        }
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let mut received = Vec::new();
+        loop {
+            match client.recv(&mut buf) {
+                Ok(len) => received.push(buf[..len].to_vec()),
+                Err(err) if would_block(&err) => return received,
+                Err(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn relays_both_ways() {
+        let listen = (Ipv4Addr::LOCALHOST, 0).into();
+        let relay = UdpRelay::spawn(listen, echo_server(), NetworkConditions::default()).unwrap();
+        let echoed = round_trip(&relay, 5);
+        assert_eq!(echoed, (0..5).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn impairs_what_it_relays() {
+        let listen = (Ipv4Addr::LOCALHOST, 0).into();
+        let lossy = NetworkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        let relay = UdpRelay::spawn(listen, echo_server(), lossy).unwrap();
+        assert!(round_trip(&relay, 5).is_empty());
+
+        relay.set_conditions(NetworkConditions::default());
+        assert_eq!(round_trip(&relay, 1), [vec![0]]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::check, ConfigError, Environment, Handling, BASE_SPEED, BOOST_COST, BOOST_REGEN,
+    BOOST_SPEED, BUMP_COST, COUNTDOWN_MS, MAX_PLAYERS, REGION_MARKERS,
+};
+
+/// Fastest swim a velocity snapshot can carry without saturating.
+const MAX_SPEED: f32 = 2000.0;
+/// How far behind the start racers can swim, past the back of the
+/// fullest starting grid.
+const START_MARGIN: f32 = 600.0;
+/// How far past the finish racers can swim on.
+const FINISH_MARGIN: f32 = 400.0;
+
+/// Course layouts a room can race on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Track {
+    /// The whole way to the ampulla.
+    #[default]
+    Full,
+    /// Every region at half length.
+    Sprint,
+}
+
+impl Track {
+    pub const ALL: [Track; 2] = [Track::Full, Track::Sprint];
+
+    pub fn length(self) -> f32 {
+        self.markers()[5]
+    }
+
+    /// Lowest and highest x racers can reach, well within what a position
+    /// snapshot can carry.
+    pub fn extent(self) -> (f32, f32) {
+        (-START_MARGIN, self.length() + FINISH_MARGIN)
+    }
+
+    /// Where each region starts, ending with the ampulla.
+    pub fn markers(self) -> [f32; 6] {
+        let scale = match self {
+            Track::Full => 1.0,
+            Track::Sprint => 0.5,
+        };
+        REGION_MARKERS.map(|marker| marker * scale)
+    }
+
+    /// The one after this, wrapping around.
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|t| *t == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// When a race is over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameMode {
+    /// As soon as the first racer reaches the ampulla.
+    #[default]
+    FirstToFinish,
+    /// Once every connected racer has.
+    AllFinish,
+}
+
+impl GameMode {
+    pub const ALL: [GameMode; 2] = [GameMode::FirstToFinish, GameMode::AllFinish];
+
+    /// The one after this, wrapping around.
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|m| *m == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Track::Full => write!(f, "full track"),
+            Track::Sprint => write!(f, "sprint track"),
+        }
+    }
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameMode::FirstToFinish => write!(f, "first to finish"),
+            GameMode::AllFinish => write!(f, "everyone finishes"),
+        }
+    }
+}
+
+/// Race parameters of one room, chosen by its host in the lobby. Server
+/// and client simulate with the same values, so prediction stays exact.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomSettings {
+    /// Speeds a held key settles at, without and with boost.
+    pub base_speed: f32,
+    pub boost_speed: f32,
+    /// Stamina per second spent boosting, and regained otherwise.
+    pub boost_cost: f32,
+    pub boost_regen: f32,
+    /// Stamina lost by whoever rams another racer; 0 makes bumps free.
+    pub bump_cost: f32,
+    pub countdown_ms: u32,
+    pub max_racers: usize,
+    pub track: Track,
+    pub mode: GameMode,
+    pub handling: Handling,
+    pub environment: Environment,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            base_speed: BASE_SPEED,
+            boost_speed: BOOST_SPEED,
+            boost_cost: BOOST_COST,
+            boost_regen: BOOST_REGEN,
+            bump_cost: BUMP_COST,
+            countdown_ms: COUNTDOWN_MS,
+            max_racers: MAX_PLAYERS,
+            track: Track::default(),
+            mode: GameMode::default(),
+            handling: Handling::default(),
+            environment: Environment::default(),
+        }
+    }
+}
+
+impl RoomSettings {
+    /// Checks the settings make a playable race on a server taking at most
+    /// `max_players` racers per room.
+    pub fn validate(&self, max_players: usize) -> Result<(), ConfigError> {
+        let handling = &self.handling;
+        check(
+            (1.0..=MAX_SPEED).contains(&handling.max_speed),
+            "handling.max_speed",
+            "between 1 and 2000",
+            handling.max_speed,
+        )?;
+        check(
+            (1.0..=handling.max_speed).contains(&self.base_speed),
+            "base_speed",
+            "between 1 and handling.max_speed",
+            self.base_speed,
+        )?;
+        check(
+            (self.base_speed..=handling.max_speed).contains(&self.boost_speed),
+            "boost_speed",
+            "between base_speed and handling.max_speed",
+            self.boost_speed,
+        )?;
+        check(
+            (0.1..=10.0).contains(&handling.linear_drag),
+            "handling.linear_drag",
+            "between 0.1 and 10",
+            handling.linear_drag,
+        )?;
+        check(
+            (0.0..=0.01).contains(&handling.quadratic_drag),
+            "handling.quadratic_drag",
+            "between 0 and 0.01",
+            handling.quadratic_drag,
+        )?;
+        check(
+            (0.5..=50.0).contains(&handling.turn_rate),
+            "handling.turn_rate",
+            "between 0.5 and 50",
+            handling.turn_rate,
+        )?;
+        for (field, stamina) in [
+            ("boost_cost", self.boost_cost),
+            ("boost_regen", self.boost_regen),
+            ("bump_cost", self.bump_cost),
+        ] {
+            check(
+                (0.0..=1000.0).contains(&stamina),
+                field,
+                "between 0 and 1000",
+                stamina,
+            )?;
+        }
+        check(
+            (1..=60_000).contains(&self.countdown_ms),
+            "countdown_ms",
+            "between 1 and 60000",
+            self.countdown_ms,
+        )?;
+        check(
+            (1..=max_players).contains(&self.max_racers),
+            "max_racers",
+            "at least 1 and within the server's max_players",
+            self.max_racers,
+        )?;
+        self.environment.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+    use crate::{region_for_position, RegionId, TRACK_LENGTH};
+
+    #[test]
+    fn sprint_track_is_the_full_one_halved() {
+        assert_eq!(Track::Full.length(), TRACK_LENGTH);
+        assert_eq!(Track::Sprint.length(), TRACK_LENGTH / 2.0);
+        let past_utj = Vec3::new(REGION_MARKERS[4] / 2.0 + 1.0, 0.0, 0.0);
+        assert_eq!(region_for_position(past_utj, Track::Sprint), RegionId::Tube);
+        assert_eq!(region_for_position(past_utj, Track::Full), RegionId::Uterus);
+        assert_eq!(Track::Sprint.next(), Track::Full);
+    }
+
+    #[test]
+    fn settings_must_make_a_playable_race() {
+        RoomSettings::default().validate(MAX_PLAYERS).unwrap();
+        let slower_boost = RoomSettings {
+            boost_speed: BASE_SPEED - 1.0,
+            ..Default::default()
+        };
+        assert!(slower_boost.validate(MAX_PLAYERS).is_err());
+        let crowded = RoomSettings {
+            max_racers: MAX_PLAYERS + 1,
+            ..Default::default()
+        };
+        assert!(crowded.validate(MAX_PLAYERS).is_err());
+        let no_countdown = RoomSettings {
+            countdown_ms: 0,
+            ..Default::default()
+        };
+        assert!(no_countdown.validate(MAX_PLAYERS).is_err());
+    }
+}
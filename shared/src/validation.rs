@@ -0,0 +1,161 @@
+use thiserror::Error;
+
+use crate::{InputFrame, INPUT_REDUNDANCY};
+
+pub const MAX_NAME_CHARS: usize = 24;
+/// Input ticks may be this far from the server tick either way, in seconds.
+pub const INPUT_WINDOW_SECONDS: u32 = 2;
+/// Violations after which the server drops a client.
+pub const MAX_STRIKES: u32 = 5;
+/// A strike is forgiven for every this many seconds without another.
+pub const STRIKE_DECAY_SECONDS: f64 = 30.0;
+
+/// Something a well-behaved client never sends.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Violation {
+    #[error("message could not be decoded")]
+    Malformed,
+    #[error("{count} input frames in one message, at most {INPUT_REDUNDANCY} allowed")]
+    TooManyFrames { count: usize },
+    #[error("input ticks are not increasing")]
+    UnorderedInputs,
+    #[error("input tick {tick} is too far ahead of server tick {server_tick}")]
+    InputTickOutOfWindow { tick: u32, server_tick: u32 },
+    #[error("more than {0} messages per second")]
+    RateLimited(u32),
+}
+
+/// Trims `name` and checks it is 1 to `MAX_NAME_CHARS` letters, digits,
+/// spaces, `-`, `_` or `.`.
+pub fn validate_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    let allowed = |c: char| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.');
+    let chars = name.chars().count();
+    ((1..=MAX_NAME_CHARS).contains(&chars) && name.chars().all(allowed)).then(|| name.into())
+}
+
+/// Checks an `Inputs` message holds no more frames than a client resends,
+/// in increasing tick order, none more than `window` ticks ahead of
+/// `server_tick`. Returns the frames worth buffering: those more than
+/// `window` ticks behind are stale, which a client still syncing its clock
+/// or on a slow link can't help.
+pub fn validate_inputs(
+    frames: &[InputFrame],
+    server_tick: u32,
+    window: u32,
+) -> Result<&[InputFrame], Violation> {
+    if frames.len() > INPUT_REDUNDANCY {
+        return Err(Violation::TooManyFrames {
+            count: frames.len(),
+        });
+    }
+    if frames.windows(2).any(|pair| pair[0].tick >= pair[1].tick) {
+        return Err(Violation::UnorderedInputs);
+    }
+    if let Some(frame) = frames
+        .iter()
+        .find(|frame| frame.tick > server_tick.saturating_add(window))
+    {
+        return Err(Violation::InputTickOutOfWindow {
+            tick: frame.tick,
+            server_tick,
+        });
+    }
+    let stale = frames
+        .iter()
+        .take_while(|frame| frame.tick < server_tick.saturating_sub(window))
+        .count();
+    Ok(&frames[stale..])
+}
+
+/// Token bucket allowing `per_second` messages a second on average, in
+/// bursts of up to a second's worth.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_second: u32,
+    tokens: f64,
+    last: Option<f64>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second,
+            tokens: f64::from(per_second),
+            last: None,
+        }
+    }
+
+    pub fn per_second(&self) -> u32 {
+        self.per_second
+    }
+
+    /// Takes a token for a message arriving at `now` seconds, if one is left.
+    pub fn allow(&mut self, now: f64) -> bool {
+        let rate = f64::from(self.per_second);
+        if let Some(last) = self.last {
+            self.tokens = (self.tokens + (now - last).max(0.0) * rate).min(rate);
+        }
+        self.last = Some(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(tick: u32) -> InputFrame {
+        InputFrame {
+            tick,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn names_are_trimmed_and_restricted() {
+        assert_eq!(validate_name("  Ana María "), Some("Ana María".into()));
+        assert_eq!(validate_name("Bot-7"), Some("Bot-7".into()));
+        assert_eq!(validate_name("   "), None);
+        assert_eq!(validate_name("a\u{7}b"), None);
+        assert_eq!(validate_name(&"x".repeat(MAX_NAME_CHARS + 1)), None);
+    }
+
+    #[test]
+    fn inputs_must_be_few_ordered_and_near_the_server_tick() {
+        let ok: Vec<_> = (98..103).map(frame).collect();
+        assert_eq!(validate_inputs(&ok, 100, 60).map(<[_]>::len), Ok(5));
+
+        let too_many: Vec<_> = (0..=INPUT_REDUNDANCY as u32).map(frame).collect();
+        assert!(matches!(
+            validate_inputs(&too_many, 0, 60),
+            Err(Violation::TooManyFrames { .. })
+        ));
+        assert_eq!(
+            validate_inputs(&[frame(101), frame(100)], 100, 60).err(),
+            Some(Violation::UnorderedInputs)
+        );
+        assert!(matches!(
+            validate_inputs(&[frame(1_000)], 100, 60),
+            Err(Violation::InputTickOutOfWindow { tick: 1_000, .. })
+        ));
+        // Stale frames are dropped, not held against the client
+        let late = [frame(30), frame(39), frame(40)];
+        assert_eq!(validate_inputs(&late, 100, 60).map(<[_]>::len), Ok(1));
+    }
+
+    #[test]
+    fn rate_limiter_allows_bursts_then_refills() {
+        let mut limiter = RateLimiter::new(10);
+        assert!((0..10).all(|_| limiter.allow(0.0)));
+        assert!(!limiter.allow(0.0));
+
+        // Half a second buys five more
+        assert!((0..5).all(|_| limiter.allow(0.5)));
+        assert!(!limiter.allow(0.5));
+    }
+}